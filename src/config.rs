@@ -2,16 +2,20 @@
 //
 // Copyright 2019,2020 Hristo Venev
 
-use crate::model::{Endpoint, Ipv4Set, Ipv6Set, Key, Secret};
-use serde_derive;
+use crate::model::{HostEndpoint, Ipv4Set, Ipv6Set, Key, Secret, VerifyKey};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::path::PathBuf;
+use std::str::FromStr;
 
-#[derive(serde_derive::Deserialize)]
+#[derive(serde_derive::Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Source {
     pub name: String,
-    pub url: String,
+    #[serde(rename = "url", deserialize_with = "deserialize_urls")]
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub shuffle_mirrors: bool,
     #[serde(default, deserialize_with = "deserialize_key_from_file")]
     pub psk: Option<Secret>,
     pub ipv4: Ipv4Set,
@@ -20,13 +24,129 @@ pub struct Source {
     pub required: bool,
     #[serde(default = "default_allow_road_warriors")]
     pub allow_road_warriors: bool,
+    #[serde(default)]
+    pub verify_key: Option<VerifyKey>,
+    #[serde(default)]
+    pub signature_url: Option<String>,
+    #[serde(default)]
+    pub monotonic_serial: bool,
+    #[serde(default)]
+    pub on_error: Option<ErrorPolicy>,
+    // Sources with a higher priority are considered first.
+    #[serde(default)]
+    pub priority: i32,
+    // Only peers with one of these tags are used, if any are given.
+    #[serde(default)]
+    pub include_tags: Vec<String>,
+    // Peers with any of these tags are not used.
+    #[serde(default)]
+    pub exclude_tags: Vec<String>,
+}
+
+impl Source {
+    // Whether a peer with these tags passes the tag filters.
+    pub fn wants_tags(&self, tags: &[String]) -> bool {
+        (self.include_tags.is_empty() || self.include_tags.iter().any(|t| tags.contains(t)))
+            && !self.exclude_tags.iter().any(|t| tags.contains(t))
+    }
+}
+
+// What to do when a source contains invalid peers.
+#[derive(serde_derive::Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    // Apply the rest of the configuration.
+    #[default]
+    Apply,
+    // Leave out the invalid peers.
+    DropPeers,
+    // Keep the last applied configuration of the source.
+    RejectSource,
+    // Do not change the interface at all.
+    RejectUpdate,
+}
+
+impl ErrorPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Apply => "apply",
+            Self::DropPeers => "drop_peers",
+            Self::RejectSource => "reject_source",
+            Self::RejectUpdate => "reject_update",
+        }
+    }
+}
+
+// What to do when the allowed IPs of different peers overlap.
+#[derive(serde_derive::Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    // Report the overlap and configure the IPs anyway.
+    Warn,
+    // Discard the IPs from the peer that claims them later.
+    KeepFirst,
+    // Configure the IPs without reporting anything.
+    #[default]
+    Ignore,
+}
+
+// What to do when several sources define the same peer. The definition from the
+// source considered first always provides the endpoint, PSK and keepalive.
+#[derive(serde_derive::Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicy {
+    // Report the later definitions, but still add their allowed IPs.
+    #[default]
+    FirstWins,
+    // Ignore definitions from sources with a lower priority. Definitions from
+    // sources with the same priority are reported.
+    PriorityWins,
+    // Add the allowed IPs of all definitions without reporting anything.
+    Union,
+}
+
+impl FromStr for MergePolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Ok(match s {
+            "first_wins" => Self::FirstWins,
+            "priority_wins" => Self::PriorityWins,
+            "union" => Self::Union,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Ok(match s {
+            "warn" => Self::Warn,
+            "keep_first" => Self::KeepFirst,
+            "ignore" => Self::Ignore,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Ok(match s {
+            "apply" => Self::Apply,
+            "drop_peers" => Self::DropPeers,
+            "reject_source" => Self::RejectSource,
+            "reject_update" => Self::RejectUpdate,
+            _ => return Err(()),
+        })
+    }
 }
 
 #[derive(serde_derive::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Peer {
     pub source: Option<String>,
-    pub endpoint: Option<Endpoint>,
+    pub endpoint: Option<HostEndpoint>,
     #[serde(default, deserialize_with = "deserialize_key_from_file")]
     pub psk: Option<Secret>,
     pub keepalive: Option<u32>,
@@ -36,6 +156,21 @@ pub struct GlobalConfig {
     pub min_keepalive: u32,
     pub max_keepalive: u32,
     pub peers: HashMap<Key, Peer>,
+
+    // Number of seconds between checks of the interface for changes made by
+    // someone else. Disabled if 0.
+    pub reconcile_sec: u32,
+
+    // Number of seconds between lookups of hostname endpoints. Disabled if 0.
+    pub resolve_sec: u32,
+    // Hostname endpoints of peers without a handshake for this many seconds
+    // are looked up again early. Disabled if 0.
+    pub stale_handshake_sec: u32,
+
+    // The default for sources that do not set their own.
+    pub on_error: ErrorPolicy,
+    pub on_overlap: OverlapPolicy,
+    pub merge: MergePolicy,
 }
 
 impl Default for GlobalConfig {
@@ -45,6 +180,12 @@ impl Default for GlobalConfig {
             min_keepalive: default_min_keepalive(),
             max_keepalive: default_max_keepalive(),
             peers: HashMap::new(),
+            reconcile_sec: default_reconcile_sec(),
+            resolve_sec: default_resolve_sec(),
+            stale_handshake_sec: default_stale_handshake_sec(),
+            on_error: ErrorPolicy::default(),
+            on_overlap: OverlapPolicy::default(),
+            merge: MergePolicy::default(),
         }
     }
 }
@@ -79,20 +220,43 @@ impl Default for UpdaterConfig {
 }
 
 #[derive(serde_derive::Deserialize)]
-#[serde(from = "ConfigRepr")]
+#[serde(try_from = "ConfigRepr")]
 #[derive(Default)]
 pub struct Config {
     pub runtime_directory: Option<PathBuf>,
+    // Where to write metrics for the Prometheus textfile collector.
+    pub metrics_file: Option<PathBuf>,
     pub global: GlobalConfig,
     pub updater: UpdaterConfig,
     pub sources: Vec<Source>,
 }
 
+impl Config {
+    // Checks the constraints between options.
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, s) in self.sources.iter().enumerate() {
+            if self.sources[..i].iter().any(|o| o.name == s.name) {
+                return Err(format!("duplicate source name [{}]", s.name));
+            }
+        }
+        if let Some(ref path) = self.metrics_file {
+            if !path.is_absolute() || path.file_name().is_none() {
+                return Err(format!(
+                    "metrics file must be an absolute file path: {}",
+                    path.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(serde_derive::Deserialize)]
 #[serde(deny_unknown_fields)]
 struct ConfigRepr {
     runtime_directory: Option<PathBuf>,
     cache_directory: Option<PathBuf>,
+    metrics_file: Option<PathBuf>,
 
     #[serde(default = "default_min_keepalive")]
     min_keepalive: u32,
@@ -103,36 +267,65 @@ struct ConfigRepr {
 
     #[serde(default = "default_refresh_sec")]
     refresh_sec: u32,
+    #[serde(default = "default_reconcile_sec")]
+    reconcile_sec: u32,
+    #[serde(default = "default_resolve_sec")]
+    resolve_sec: u32,
+    #[serde(default = "default_stale_handshake_sec")]
+    stale_handshake_sec: u32,
+    #[serde(default)]
+    on_error: ErrorPolicy,
+    #[serde(default)]
+    on_overlap: OverlapPolicy,
+    #[serde(default)]
+    merge: MergePolicy,
 
     #[serde(default, rename = "source")]
     sources: Vec<Source>,
 }
 
-impl From<ConfigRepr> for Config {
-    #[inline]
-    fn from(v: ConfigRepr) -> Self {
+impl TryFrom<ConfigRepr> for Config {
+    type Error = String;
+
+    fn try_from(v: ConfigRepr) -> Result<Self, String> {
         let ConfigRepr {
             runtime_directory,
             cache_directory,
+            metrics_file,
             min_keepalive,
             max_keepalive,
             peers,
             refresh_sec,
+            reconcile_sec,
+            resolve_sec,
+            stale_handshake_sec,
+            on_error,
+            on_overlap,
+            merge,
             sources,
         } = v;
-        Self {
+        let r = Self {
             runtime_directory,
+            metrics_file,
             global: GlobalConfig {
                 min_keepalive,
                 max_keepalive,
                 peers,
+                reconcile_sec,
+                resolve_sec,
+                stale_handshake_sec,
+                on_error,
+                on_overlap,
+                merge,
             },
             updater: UpdaterConfig {
                 cache_directory,
                 refresh_sec,
             },
             sources,
-        }
+        };
+        r.validate()?;
+        Ok(r)
     }
 }
 
@@ -156,6 +349,21 @@ const fn default_refresh_sec() -> u32 {
     1200
 }
 
+#[inline]
+const fn default_reconcile_sec() -> u32 {
+    300
+}
+
+#[inline]
+const fn default_resolve_sec() -> u32 {
+    300
+}
+
+#[inline]
+const fn default_stale_handshake_sec() -> u32 {
+    180
+}
+
 fn deserialize_key_from_file<'de, D>(d: D) -> Result<Option<Secret>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -163,3 +371,26 @@ where
     let path = <PathBuf as serde::Deserialize<'de>>::deserialize(d)?;
     Secret::from_file(&path).map_err(|e| <D::Error as serde::de::Error>::custom(e.to_string()))
 }
+
+fn deserialize_urls<'de, D>(d: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde_derive::Deserialize)]
+    #[serde(untagged)]
+    enum Urls {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let urls = match <Urls as serde::Deserialize<'de>>::deserialize(d)? {
+        Urls::One(url) => vec![url],
+        Urls::Many(urls) => urls,
+    };
+    if urls.is_empty() {
+        return Err(<D::Error as serde::de::Error>::custom(
+            "at least one URL required",
+        ));
+    }
+    Ok(urls)
+}
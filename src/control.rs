@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use std::io;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+// How long a client has to send its request and read the reply.
+#[cfg(target_os = "linux")]
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// How soon to try again to send a reply that did not fit in the socket buffer.
+#[cfg(target_os = "linux")]
+const RETRY: Duration = Duration::from_millis(50);
+
+#[cfg(target_os = "linux")]
+const MAX_REQUEST: usize = 1024;
+
+// Clients send a single line with the request and get a JSON reply, after which
+// the connection is closed. Connections are served a bit at a time so that a
+// slow client cannot hold up the main loop.
+#[cfg(target_os = "linux")]
+pub struct Server {
+    listener: std::os::unix::net::UnixListener,
+    path: PathBuf,
+    conns: Vec<Conn>,
+}
+
+#[cfg(target_os = "linux")]
+struct Conn {
+    stream: std::os::unix::net::UnixStream,
+    request: Vec<u8>,
+    // What is left to send, once the request has been read.
+    reply: Option<Vec<u8>>,
+    deadline: Instant,
+}
+
+#[cfg(target_os = "linux")]
+impl Server {
+    pub fn bind(path: PathBuf) -> io::Result<Self> {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        match fs::remove_file(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            r => r?,
+        }
+        let listener = std::os::unix::net::UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path,
+            conns: vec![],
+        })
+    }
+
+    // The descriptors to wait on: the socket and the connections whose request
+    // has not been read yet.
+    pub fn fds(&self) -> Vec<c_int> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut r = vec![self.listener.as_raw_fd()];
+        for c in &self.conns {
+            if c.reply.is_none() {
+                r.push(c.stream.as_raw_fd());
+            }
+        }
+        r
+    }
+
+    // When to call `poll` again even if nothing happens.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.conns
+            .iter()
+            .map(|c| match c.reply {
+                Some(_) => c.deadline.min(now + RETRY),
+                None => c.deadline,
+            })
+            .min()
+    }
+
+    // Accepts the pending connections and makes progress on the open ones,
+    // without waiting. `f` gets the request and returns the reply.
+    pub fn poll(&mut self, mut f: impl FnMut(&str) -> Vec<u8>) -> io::Result<()> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            stream.set_nonblocking(true)?;
+            self.conns.push(Conn {
+                stream,
+                request: vec![],
+                reply: None,
+                deadline: Instant::now() + TIMEOUT,
+            });
+        }
+
+        let now = Instant::now();
+        self.conns.retain_mut(|c| {
+            let e = match c.serve(&mut f) {
+                Ok(false) => return false,
+                Ok(true) if now < c.deadline => return true,
+                Ok(true) => io::Error::new(io::ErrorKind::TimedOut, "timed out"),
+                Err(e) => e,
+            };
+            log!(
+                4,
+                "control_failed",
+                { error: e.to_string() },
+                "Control connection failed: {}",
+                e
+            );
+            false
+        });
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Conn {
+    // Does at most one read and one write. Returns whether there is more to do.
+    fn serve(&mut self, f: &mut impl FnMut(&str) -> Vec<u8>) -> io::Result<bool> {
+        use std::io::{Read, Write};
+
+        if self.reply.is_none() {
+            let mut buf = [0_u8; MAX_REQUEST];
+            let buf = &mut buf[..MAX_REQUEST - self.request.len()];
+            let n = match self.stream.read(buf) {
+                Ok(n) => n,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::Interrupted =>
+                {
+                    return Ok(true)
+                }
+                Err(e) => return Err(e),
+            };
+            if n == 0 && self.request.is_empty() {
+                return Ok(false);
+            }
+            self.request.extend_from_slice(&buf[..n]);
+
+            let end = self.request.iter().position(|c| *c == b'\n');
+            if end.is_none() && n != 0 && self.request.len() < MAX_REQUEST {
+                return Ok(true);
+            }
+            let line = &self.request[..end.unwrap_or(self.request.len())];
+            let line = std::str::from_utf8(line)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid request"))?;
+            self.reply = Some(f(line.trim()));
+        }
+
+        let reply = self.reply.as_mut().unwrap();
+        match self.stream.write(reply) {
+            Ok(n) => {
+                reply.drain(..n);
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        Ok(!reply.is_empty())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn query(path: &Path, request: &str) -> io::Result<Vec<u8>> {
+    use std::io::{Read, Write};
+
+    let mut conn = std::os::unix::net::UnixStream::connect(path)?;
+    conn.set_read_timeout(Some(Duration::from_secs(10)))?;
+    writeln!(conn, "{}", request)?;
+    let mut reply = vec![];
+    conn.read_to_end(&mut reply)?;
+    Ok(reply)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub enum Server {}
+
+#[cfg(not(target_os = "linux"))]
+impl Server {
+    #[inline]
+    pub fn bind(_path: PathBuf) -> io::Result<Self> {
+        Err(io::Error::other("control socket not supported"))
+    }
+
+    #[inline]
+    pub fn fds(&self) -> Vec<c_int> {
+        match *self {}
+    }
+
+    #[inline]
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        match *self {}
+    }
+
+    #[inline]
+    pub fn poll(&mut self, _f: impl FnMut(&str) -> Vec<u8>) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn query(_path: &Path, _request: &str) -> io::Result<Vec<u8>> {
+    Err(io::Error::other("control socket not supported"))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{query, Server};
+    use crate::testutil::TempDir;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_query() {
+        let dir = TempDir::new();
+        let path = dir.0.join("control.sock");
+
+        let mut server = Server::bind(path.clone()).unwrap();
+
+        // A client that does not send anything does not hold up others.
+        let idle = UnixStream::connect(&path).unwrap();
+        let client = {
+            let path = path.clone();
+            thread::spawn(move || query(&path, "status").unwrap())
+        };
+
+        let mut requests = vec![];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while requests.is_empty() && Instant::now() < deadline {
+            crate::watch::wait(&server.fds(), deadline).unwrap();
+            server
+                .poll(|req| {
+                    requests.push(req.to_owned());
+                    b"{}".to_vec()
+                })
+                .unwrap();
+        }
+        assert_eq!(requests, vec!["status"]);
+        assert_eq!(client.join().unwrap(), b"{}");
+        assert_eq!(server.conns.len(), 1);
+
+        drop(idle);
+        server.poll(|_| panic!("unexpected request")).unwrap();
+        assert!(server.conns.is_empty());
+    }
+}
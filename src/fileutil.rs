@@ -18,7 +18,13 @@ impl Drop for Temp {
             return;
         }
         if let Err(err) = fs::remove_file(&self.path) {
-            eprintln!("<3>Failed to clean up temporary file: {}", err);
+            log!(
+                3,
+                "cleanup_failed",
+                { path: &self.path, error: err.to_string() },
+                "Failed to clean up temporary file: {}",
+                err
+            );
         }
     }
 }
@@ -107,7 +113,7 @@ pub fn load(path: &impl AsRef<Path>) -> io::Result<Vec<u8>> {
 }
 
 fn _load(path: &Path) -> io::Result<Vec<u8>> {
-    let mut file = fs::File::open(&path)?;
+    let mut file = fs::File::open(path)?;
     let mut data = Vec::new();
     io::Read::read_to_end(&mut file, &mut data)?;
     Ok(data)
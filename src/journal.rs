@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+// Sends entries to journald using its native protocol.
+pub struct Journal {
+    sock: UnixDatagram,
+}
+
+impl Journal {
+    pub fn connect() -> io::Result<Self> {
+        let sock = UnixDatagram::unbound()?;
+        sock.connect(SOCKET)?;
+        Ok(Self { sock })
+    }
+
+    // Field names must consist of uppercase letters, digits and underscores.
+    pub fn send(&self, fields: &[(&str, &str)]) -> io::Result<()> {
+        self.sock.send(&encode(fields))?;
+        Ok(())
+    }
+}
+
+fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut r = vec![];
+    for (k, v) in fields {
+        r.extend_from_slice(k.as_bytes());
+        if v.contains('\n') {
+            r.push(b'\n');
+            r.extend_from_slice(&(v.len() as u64).to_le_bytes());
+        } else {
+            r.push(b'=');
+        }
+        r.extend_from_slice(v.as_bytes());
+        r.push(b'\n');
+    }
+    r
+}
+
+// Whether stderr is connected to the journal, as opposed to having been
+// redirected elsewhere by the service.
+pub fn is_stderr() -> bool {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsFd;
+
+    let v = match std::env::var("JOURNAL_STREAM") {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    let mut it = v.splitn(2, ':');
+    let (dev, ino) = match (it.next(), it.next()) {
+        (Some(dev), Some(ino)) => (dev.parse::<u64>(), ino.parse::<u64>()),
+        _ => return false,
+    };
+    let file = match io::stderr().as_fd().try_clone_to_owned() {
+        Ok(fd) => std::fs::File::from(fd),
+        Err(_) => return false,
+    };
+    match file.metadata() {
+        Ok(m) => dev == Ok(m.dev()) && ino == Ok(m.ino()),
+        Err(_) => false,
+    }
+}
+
+// A stable 128-bit message ID for an event name.
+pub fn message_id(event: &str) -> String {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in event.bytes() {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("6c0f3bd1e0a54f4e{:016x}", h)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, message_id};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode(&[("MESSAGE", "hello"), ("WGCONFD_ERROR", "a\nb")]),
+            b"MESSAGE=hello\nWGCONFD_ERROR\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec()
+        );
+        assert_eq!(message_id("source_updated").len(), 32);
+        assert_eq!(message_id("source_updated"), message_id("source_updated"));
+        assert_ne!(message_id("source_updated"), message_id("source_failed"));
+    }
+}
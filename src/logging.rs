@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use crate::{journal, model};
+use serde_json::Value;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::{env, fmt, io};
+
+// Logs an event with a syslog priority, a name, optional fields and a
+// human-readable message:
+//
+//     log!(4, "fetch_failed", { source: name, url: url }, "Failed: {}", e);
+macro_rules! log {
+    ($prio:expr, $event:expr, { $($k:ident: $v:expr),* $(,)? }, $($arg:tt)+) => {
+        $crate::logging::emit(
+            $prio,
+            $event,
+            &[$((stringify!($k), {
+                #[allow(unused_imports)]
+                use $crate::logging::Field as _;
+                ($v).to_value()
+            })),*],
+            format_args!($($arg)+),
+        )
+    };
+    ($prio:expr, $event:expr, $($arg:tt)+) => {
+        $crate::logging::emit($prio, $event, &[], format_args!($($arg)+))
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Format {
+    // Free-form text with a syslog priority prefix.
+    Syslog = 0,
+    // One JSON object per line.
+    Json = 1,
+    // Native journald entries with one field per value.
+    Journal = 2,
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Syslog as u8);
+static JOURNAL: OnceLock<journal::Journal> = OnceLock::new();
+static INTERFACE: OnceLock<String> = OnceLock::new();
+
+#[inline]
+pub fn format() -> Format {
+    match FORMAT.load(Ordering::Relaxed) {
+        1 => Format::Json,
+        2 => Format::Journal,
+        _ => Format::Syslog,
+    }
+}
+
+#[inline]
+pub fn set_format(f: Format) {
+    FORMAT.store(f as u8, Ordering::Relaxed);
+}
+
+fn use_journal() -> io::Result<()> {
+    let j = journal::Journal::connect()?;
+    let _ = JOURNAL.set(j);
+    set_format(Format::Journal);
+    Ok(())
+}
+
+// Attaches the interface name to all subsequent events.
+pub fn set_interface(name: &str) {
+    let _ = INTERFACE.set(name.to_owned());
+}
+
+#[inline]
+pub fn structured() -> bool {
+    format() != Format::Syslog
+}
+
+// Sets up logging according to $WGCONFD_LOG_FORMAT. By default, the journal
+// is used if stderr is connected to it.
+pub fn init() {
+    let v = env::var("WGCONFD_LOG_FORMAT").unwrap_or_default();
+    match &v[..] {
+        "" => {
+            if journal::is_stderr() {
+                let _ = use_journal();
+            }
+        }
+        "syslog" => set_format(Format::Syslog),
+        "json" => set_format(Format::Json),
+        "journal" => {
+            if let Err(e) = use_journal() {
+                log!(
+                    4,
+                    "journal_failed",
+                    { error: e.to_string() },
+                    "Failed to connect to the journal: {}",
+                    e
+                );
+            }
+        }
+        _ => log!(4, "log_format_invalid", { format: v }, "Unknown log format: {}", v),
+    }
+}
+
+// A value attached to a log event.
+pub trait Field {
+    fn to_value(&self) -> Value;
+}
+
+macro_rules! impl_field {
+    ($($t:ty),*) => {$(
+        impl Field for $t {
+            #[inline]
+            fn to_value(&self) -> Value {
+                Value::from(*self)
+            }
+        }
+    )*};
+}
+impl_field!(bool, u32, u64, usize, f64);
+
+macro_rules! impl_field_display {
+    ($($t:ty),*) => {$(
+        impl Field for $t {
+            #[inline]
+            fn to_value(&self) -> Value {
+                Value::String(self.to_string())
+            }
+        }
+    )*};
+}
+impl_field_display!(str, String, model::Key, model::Endpoint);
+
+// Missing values are left out.
+impl<T: Field> Field for Option<T> {
+    #[inline]
+    fn to_value(&self) -> Value {
+        match self {
+            Some(v) => v.to_value(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl<T: Field + ?Sized> Field for &T {
+    #[inline]
+    fn to_value(&self) -> Value {
+        (**self).to_value()
+    }
+}
+
+impl Field for Path {
+    #[inline]
+    fn to_value(&self) -> Value {
+        Value::String(self.display().to_string())
+    }
+}
+
+impl Field for PathBuf {
+    #[inline]
+    fn to_value(&self) -> Value {
+        self.as_path().to_value()
+    }
+}
+
+fn journal_entry(
+    priority: u8,
+    event: &str,
+    fields: &[(&str, Value)],
+    msg: &str,
+) -> Vec<(String, String)> {
+    let mut r = vec![
+        ("MESSAGE".to_owned(), msg.to_owned()),
+        ("PRIORITY".to_owned(), priority.to_string()),
+        ("MESSAGE_ID".to_owned(), journal::message_id(event)),
+        ("SYSLOG_IDENTIFIER".to_owned(), "wgconfd".to_owned()),
+        ("WGCONFD_EVENT".to_owned(), event.to_owned()),
+    ];
+    if let Some(name) = INTERFACE.get() {
+        r.push(("WGCONFD_INTERFACE".to_owned(), name.clone()));
+    }
+    for (k, v) in fields {
+        let v = match v {
+            Value::Null => continue,
+            Value::String(v) => v.clone(),
+            v => v.to_string(),
+        };
+        r.push((format!("WGCONFD_{}", k.to_ascii_uppercase()), v));
+    }
+    r
+}
+
+pub fn emit(priority: u8, event: &str, fields: &[(&str, Value)], msg: fmt::Arguments<'_>) {
+    let line = match format() {
+        Format::Syslog => format!("<{}>{}\n", priority, msg),
+        Format::Journal => {
+            let msg = msg.to_string();
+            let entry = journal_entry(priority, event, fields, &msg);
+            let entry: Vec<(&str, &str)> = entry.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+            match JOURNAL.get().map(|j| j.send(&entry)) {
+                Some(Ok(())) => return,
+                _ => format!("<{}>{}\n", priority, msg),
+            }
+        }
+        Format::Json => {
+            let mut obj = serde_json::Map::new();
+            if let Some(name) = INTERFACE.get() {
+                obj.insert("interface".to_owned(), Value::from(&name[..]));
+            }
+            for (k, v) in fields {
+                if !v.is_null() {
+                    obj.insert((*k).to_owned(), v.clone());
+                }
+            }
+            obj.insert("priority".to_owned(), Value::from(priority));
+            obj.insert("event".to_owned(), Value::from(event));
+            obj.insert("message".to_owned(), Value::from(msg.to_string()));
+            let mut line = Value::Object(obj).to_string();
+            line.push('\n');
+            line
+        }
+    };
+    let _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::{journal_entry, Field};
+    use crate::model;
+
+    #[test]
+    fn test_fields() {
+        let name = &String::from("a");
+        assert_eq!(name.to_value(), "a");
+        assert_eq!("b".to_value(), "b");
+        assert_eq!(true.to_value(), true);
+        assert_eq!(5_u32.to_value(), 5);
+        assert_eq!(
+            model::Key::from_bytes([0; 32]).to_value(),
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        );
+    }
+
+    #[test]
+    fn test_journal_entry() {
+        let entry = journal_entry(
+            4,
+            "peer_missing",
+            &[
+                ("peer", "x".to_value()),
+                ("important", true.to_value()),
+                ("peer_name", None::<&str>.to_value()),
+            ],
+            "Peer [x] missing",
+        );
+        let get = |k: &str| entry.iter().find(|(n, _)| n == k).map(|(_, v)| &v[..]);
+        assert_eq!(get("MESSAGE"), Some("Peer [x] missing"));
+        assert_eq!(get("PRIORITY"), Some("4"));
+        assert_eq!(get("WGCONFD_EVENT"), Some("peer_missing"));
+        assert_eq!(get("WGCONFD_PEER"), Some("x"));
+        assert_eq!(get("WGCONFD_IMPORTANT"), Some("true"));
+        assert_eq!(get("WGCONFD_PEER_NAME"), None);
+        assert_eq!(get("MESSAGE_ID").map(str::len), Some(32));
+    }
+}
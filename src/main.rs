@@ -8,16 +8,32 @@
 extern crate arrayref;
 
 use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
-use std::{env, mem, process, thread};
+use std::{env, mem, process};
+
+#[macro_use]
+mod logging;
 
 mod config;
+mod control;
 mod fileutil;
+mod journal;
 mod manager;
+mod metrics;
 mod model;
+mod notify;
 mod proto;
+mod signal;
+mod watch;
 mod wg;
 
+#[cfg(test)]
+mod testutil;
+
+// The name of the control socket in the runtime directory.
+const CONTROL_SOCKET: &str = "control.sock";
+
 fn cli_config(mut args: impl Iterator<Item = OsString>) -> Option<config::Config> {
     enum State<'a> {
         Source(&'a mut config::Source),
@@ -56,6 +72,14 @@ fn cli_config(mut args: impl Iterator<Item = OsString>) -> Option<config::Config
                     }
                     continue;
                 }
+                if key == "mirror" {
+                    s.urls.push(args.next()?.into_string().ok()?);
+                    continue;
+                }
+                if key == "shuffle_mirrors" {
+                    s.shuffle_mirrors = true;
+                    continue;
+                }
                 if key == "required" {
                     s.required = true;
                     continue;
@@ -68,6 +92,44 @@ fn cli_config(mut args: impl Iterator<Item = OsString>) -> Option<config::Config
                     s.allow_road_warriors = true;
                     continue;
                 }
+                if key == "verify_key" {
+                    arg = args.next()?;
+                    let arg = arg.to_str()?;
+                    s.verify_key = Some(model::VerifyKey::from_str(arg).ok()?);
+                    continue;
+                }
+                if key == "monotonic_serial" {
+                    s.monotonic_serial = true;
+                    continue;
+                }
+                if key == "signature_url" {
+                    s.signature_url = Some(args.next()?.into_string().ok()?);
+                    continue;
+                }
+                if key == "on_error" {
+                    arg = args.next()?;
+                    let arg = arg.to_str()?;
+                    s.on_error = Some(config::ErrorPolicy::from_str(arg).ok()?);
+                    continue;
+                }
+                if key == "priority" {
+                    arg = args.next()?;
+                    let arg = arg.to_str()?;
+                    s.priority = i32::from_str(arg).ok()?;
+                    continue;
+                }
+                if key == "include_tags" {
+                    arg = args.next()?;
+                    let arg = arg.to_str()?;
+                    s.include_tags.extend(arg.split(',').map(str::to_owned));
+                    continue;
+                }
+                if key == "exclude_tags" {
+                    arg = args.next()?;
+                    let arg = arg.to_str()?;
+                    s.exclude_tags.extend(arg.split(',').map(str::to_owned));
+                    continue;
+                }
             }
             State::Peer(ref mut p) => {
                 if key == "source" {
@@ -77,7 +139,7 @@ fn cli_config(mut args: impl Iterator<Item = OsString>) -> Option<config::Config
                 if key == "endpoint" {
                     arg = args.next()?;
                     let arg = arg.to_str()?;
-                    p.endpoint = Some(model::Endpoint::from_str(arg).ok()?);
+                    p.endpoint = Some(model::HostEndpoint::from_str(arg).ok()?);
                     continue;
                 }
                 if key == "psk" {
@@ -114,17 +176,65 @@ fn cli_config(mut args: impl Iterator<Item = OsString>) -> Option<config::Config
             cfg.updater.refresh_sec = u32::from_str(arg).ok()?;
             continue;
         }
+        if key == "reconcile_sec" {
+            arg = args.next()?;
+            let arg = arg.to_str()?;
+            cfg.global.reconcile_sec = u32::from_str(arg).ok()?;
+            continue;
+        }
+        if key == "resolve_sec" {
+            arg = args.next()?;
+            let arg = arg.to_str()?;
+            cfg.global.resolve_sec = u32::from_str(arg).ok()?;
+            continue;
+        }
+        if key == "stale_handshake_sec" {
+            arg = args.next()?;
+            let arg = arg.to_str()?;
+            cfg.global.stale_handshake_sec = u32::from_str(arg).ok()?;
+            continue;
+        }
+        if key == "on_error" {
+            arg = args.next()?;
+            let arg = arg.to_str()?;
+            cfg.global.on_error = config::ErrorPolicy::from_str(arg).ok()?;
+            continue;
+        }
+        if key == "on_overlap" {
+            arg = args.next()?;
+            let arg = arg.to_str()?;
+            cfg.global.on_overlap = config::OverlapPolicy::from_str(arg).ok()?;
+            continue;
+        }
+        if key == "merge" {
+            arg = args.next()?;
+            let arg = arg.to_str()?;
+            cfg.global.merge = config::MergePolicy::from_str(arg).ok()?;
+            continue;
+        }
+        if key == "metrics_file" {
+            cfg.metrics_file = Some(args.next()?.into());
+            continue;
+        }
         if key == "source" {
             let name = args.next()?.into_string().ok()?;
             let url = args.next()?.into_string().ok()?;
             cfg.sources.push(config::Source {
                 name,
-                url,
+                urls: vec![url],
+                shuffle_mirrors: false,
                 psk: None,
                 ipv4: model::Ipv4Set::new(),
                 ipv6: model::Ipv6Set::new(),
                 required: false,
                 allow_road_warriors: true,
+                verify_key: None,
+                signature_url: None,
+                monotonic_serial: false,
+                on_error: None,
+                priority: 0,
+                include_tags: vec![],
+                exclude_tags: vec![],
             });
             cur = State::Source(cfg.sources.last_mut().unwrap());
             continue;
@@ -144,12 +254,15 @@ fn cli_config(mut args: impl Iterator<Item = OsString>) -> Option<config::Config
         return None;
     }
 
+    cfg.validate().ok()?;
     Some(cfg)
 }
 
 fn usage(argv0: &str) -> i32 {
-    eprintln!(
-        "<1>Invalid arguments. See `{} --help` for more information",
+    log!(
+        1,
+        "invalid_arguments",
+        "Invalid arguments. See `{} --help` for more information",
         argv0
     );
     1
@@ -162,9 +275,10 @@ fn help(argv0: &str, args: Vec<OsString>) -> i32 {
 Usage:
     {} IFNAME CONFIG         - run daemon on interface
     {} --cmdline IFNAME ...  - run daemon using config passed as arguments
+    {} --status IFNAME       - show the status of a running daemon
     {} --check-source PATH   - validate source JSON
 ",
-        argv0, argv0, argv0
+        argv0, argv0, argv0, argv0
     );
     1
 }
@@ -181,6 +295,27 @@ fn maybe_get_var(out: &mut Option<impl From<OsString>>, var: impl AsRef<OsStr>)
     }
 }
 
+#[cfg(feature = "toml")]
+fn load_config(path: &Path) -> Result<config::Config, String> {
+    let data = fileutil::load(&path);
+    let data = match data {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Failed to load config file: {}", e)),
+    };
+
+    let config = toml::from_slice(&data);
+    mem::drop(data);
+    match config {
+        Ok(v) => Ok(v),
+        Err(e) => Err(format!("Failed to parse config: {}", e)),
+    }
+}
+
+#[cfg(not(feature = "toml"))]
+fn load_config(_path: &Path) -> Result<config::Config, String> {
+    Err("Config loading not supported".to_owned())
+}
+
 #[cfg(feature = "toml")]
 fn run_with_file(argv0: &str, args: Vec<OsString>) -> i32 {
     let (ifname, path) = match (move || {
@@ -196,32 +331,21 @@ fn run_with_file(argv0: &str, args: Vec<OsString>) -> i32 {
         None => return usage(argv0),
     };
 
-    let data = fileutil::load(&path);
-    mem::drop(path);
-    let data = match data {
+    let path = PathBuf::from(path);
+    let config = match load_config(&path) {
         Ok(v) => v,
         Err(e) => {
-            eprintln!("<1>Failed to load config file: {}", e);
+            log!(1, "config_invalid", { path: path }, "{}", e);
             return 1;
         }
     };
 
-    let config = toml::from_slice(&data);
-    mem::drop(data);
-    let config = match config {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("<1>Failed to parse config: {}", e);
-            return 1;
-        }
-    };
-
-    run_daemon(ifname, config)
+    run_daemon(ifname, config, Some(path))
 }
 
 #[cfg(not(feature = "toml"))]
 fn run_with_file(_argv0: &str, _args: Vec<OsString>) -> i32 {
-    eprintln!("<1>Config loading not supported");
+    log!(1, "config_unsupported", "Config loading not supported");
     1
 }
 
@@ -236,38 +360,324 @@ fn run_with_cmdline(argv0: &str, args: Vec<OsString>) -> i32 {
     let config = match cli_config(args) {
         Some(c) => c,
         None => {
-            eprintln!("<1>Invalid config");
+            log!(1, "config_invalid", "Invalid config");
             return 1;
         }
     };
 
-    run_daemon(ifname, config)
+    run_daemon(ifname, config, None)
+}
+
+// Directories passed through the environment override the config file.
+struct EnvConfig {
+    cache_directory: Option<PathBuf>,
+    runtime_directory: Option<PathBuf>,
 }
 
-fn run_daemon(ifname: OsString, mut config: config::Config) -> i32 {
-    maybe_get_var(&mut config.updater.cache_directory, "CACHE_DIRECTORY");
-    maybe_get_var(&mut config.runtime_directory, "RUNTIME_DIRECTORY");
+impl EnvConfig {
+    fn get() -> Self {
+        let mut r = Self {
+            cache_directory: None,
+            runtime_directory: None,
+        };
+        maybe_get_var(&mut r.cache_directory, "CACHE_DIRECTORY");
+        maybe_get_var(&mut r.runtime_directory, "RUNTIME_DIRECTORY");
+        r
+    }
 
+    fn apply(&self, config: &mut config::Config) {
+        if let Some(ref v) = self.cache_directory {
+            config.updater.cache_directory = Some(v.clone());
+        }
+        if let Some(ref v) = self.runtime_directory {
+            config.runtime_directory = Some(v.clone());
+        }
+    }
+}
+
+fn watch_files(w: &mut watch::Watcher, paths: &[PathBuf]) {
+    for path in paths {
+        if let Err(e) = w.add(path) {
+            log!(
+                4,
+                "watch_failed",
+                { path: path, error: e.to_string() },
+                "Failed to watch [{}]: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn run_daemon(ifname: OsString, mut config: config::Config, config_path: Option<PathBuf>) -> i32 {
+    let interface = ifname.to_string_lossy().into_owned();
+    logging::set_interface(&interface);
+    let env_config = EnvConfig::get();
+    env_config.apply(&mut config);
+
+    let notifier = match notify::Notifier::from_env() {
+        Ok(v) => v,
+        Err(e) => {
+            log!(
+                4,
+                "notify_failed",
+                { error: e.to_string() },
+                "Failed to connect to the service manager: {}",
+                e
+            );
+            None
+        }
+    };
+    let watchdog = notify::watchdog_interval();
+
+    // Signals that arrive during the initial fetches are handled afterwards.
+    let signals = match signal::Signals::new(&[signal::SIGHUP, signal::SIGUSR1]) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log!(
+                4,
+                "signals_failed",
+                { error: e.to_string() },
+                "Failed to set up signal handlers: {}",
+                e
+            );
+            None
+        }
+    };
+
+    let control_path = config
+        .runtime_directory
+        .as_ref()
+        .map(|v| v.join(CONTROL_SOCKET));
+    let mut metrics_file = config.metrics_file.take();
     let mut m = match manager::Manager::new(ifname, config) {
         Ok(m) => m,
         Err(e) => {
-            eprintln!("<1>Failed to start: {}", e);
+            log!(1, "start_failed", { error: e.to_string() }, "Failed to start: {}", e);
             return 1;
         }
     };
 
+    let mut control = match control_path.map(control::Server::bind) {
+        Some(Ok(v)) => Some(v),
+        Some(Err(e)) => {
+            log!(
+                4,
+                "control_failed",
+                { error: e.to_string() },
+                "Failed to create control socket: {}",
+                e
+            );
+            None
+        }
+        None => None,
+    };
+
+    let mut watcher = match watch::Watcher::new() {
+        Ok(mut w) => {
+            watch_files(&mut w, &m.local_files());
+            watch_files(&mut w, config_path.as_slice());
+            Some(w)
+        }
+        Err(e) => {
+            log!(
+                4,
+                "watch_failed",
+                { error: e.to_string() },
+                "Not watching local files: {}",
+                e
+            );
+            None
+        }
+    };
+
+    let mut base_fds = vec![];
+    base_fds.extend(watcher.as_ref().map(watch::Watcher::as_raw_fd));
+    base_fds.extend(signals.as_ref().map(signal::Signals::as_raw_fd));
+
+    let mut ready = false;
+    let mut last_status = String::new();
+    // The manager is only updated when something happened or when it asked to
+    // be, not when control connections wake the loop up.
+    let mut due = true;
+    let mut next_update = Instant::now();
     loop {
-        let tm = match m.update() {
-            Ok(t) => t,
+        if due || Instant::now() >= next_update {
+            due = false;
+            let tm = match m.update() {
+                Ok(t) => t,
+                Err(e) => {
+                    log!(1, "update_failed", { error: e.to_string() }, "{}", e);
+                    return 1;
+                }
+            };
+            if let Some(ref path) = metrics_file {
+                if let Err(e) = metrics::write(path, &interface, &m.status()) {
+                    log!(
+                        4,
+                        "metrics_failed",
+                        { path: path, error: e.to_string() },
+                        "Failed to write metrics: {}",
+                        e
+                    );
+                }
+            }
+
+            if let Some(ref n) = notifier {
+                let mut msg = String::new();
+                if !ready {
+                    msg.push_str("READY=1\n");
+                    ready = true;
+                }
+                let status = notify::describe(&m.status());
+                if status != last_status {
+                    msg.push_str(&format!("STATUS={}\n", status));
+                    last_status = status;
+                }
+                if watchdog.is_some() {
+                    msg.push_str("WATCHDOG=1\n");
+                }
+                if !msg.is_empty() {
+                    if let Err(e) = n.send(&msg) {
+                        log!(
+                            4,
+                            "notify_failed",
+                            { error: e.to_string() },
+                            "Failed to notify the service manager: {}",
+                            e
+                        );
+                    }
+                }
+            }
+            next_update = match watchdog {
+                Some(w) => tm.min(Instant::now() + w / 2),
+                None => tm,
+            };
+        }
+
+        let mut fds = base_fds.clone();
+        let tm = match control {
+            Some(ref c) => {
+                fds.extend(c.fds());
+                c.next_wakeup().map_or(next_update, |t| next_update.min(t))
+            }
+            None => next_update,
+        };
+
+        let mut reload = false;
+        let r = watch::wait(&fds, tm).and_then(|()| {
+            if let Some(ref mut w) = watcher {
+                for path in w.changed()? {
+                    due = true;
+                    if config_path.as_ref() == Some(&path) {
+                        log!(5, "config_changed", { path: path }, "Config file changed");
+                        reload = true;
+                    } else {
+                        m.file_changed(&path);
+                    }
+                }
+            }
+            if let Some(ref s) = signals {
+                for sig in s.pending()? {
+                    due = true;
+                    if sig == signal::SIGHUP {
+                        log!(5, "refresh_all", "Refreshing all sources");
+                        reload = true;
+                        m.refresh_all();
+                    } else if sig == signal::SIGUSR1 {
+                        m.dump_state();
+                    }
+                }
+            }
+            if let Some(ref mut c) = control {
+                c.poll(|req| control_request(&m, req))?;
+            }
+            Ok(())
+        });
+        if let Err(e) = r {
+            log!(
+                1,
+                "wait_failed",
+                { error: e.to_string() },
+                "Failed to wait for events: {}",
+                e
+            );
+            return 1;
+        }
+
+        let path = match config_path {
+            Some(ref v) if reload => v,
+            _ => continue,
+        };
+        log!(5, "config_reload", { path: path }, "Reloading configuration");
+        let mut config = match load_config(path) {
+            Ok(v) => v,
             Err(e) => {
-                eprintln!("<1>{}", e);
-                return 1;
+                log!(3, "config_invalid", { path: path }, "{}", e);
+                continue;
             }
         };
-        let now = Instant::now();
-        if tm > now {
-            let sleep = tm.duration_since(now);
-            thread::sleep(sleep);
+        env_config.apply(&mut config);
+        let new_metrics_file = config.metrics_file.take();
+        if let Err(e) = m.reload(config) {
+            log!(
+                3,
+                "config_reload_failed",
+                { error: e.to_string() },
+                "Failed to reload configuration: {}",
+                e
+            );
+            continue;
+        }
+        metrics_file = new_metrics_file;
+        if let Some(ref mut w) = watcher {
+            let mut paths = m.local_files();
+            paths.extend(config_path.iter().cloned());
+            w.retain(&paths);
+            watch_files(w, &paths);
+        }
+    }
+}
+
+fn control_request(m: &manager::Manager, req: &str) -> Vec<u8> {
+    match req {
+        "status" => serde_json::to_vec(&m.status()).unwrap(),
+        _ => serde_json::to_vec(&serde_json::json!({ "error": "unknown request" })).unwrap(),
+    }
+}
+
+fn run_status(argv0: &str, args: Vec<OsString>) -> i32 {
+    let mut args = args.into_iter();
+    let ifname = match args.next() {
+        Some(v) => v,
+        None => return usage(argv0),
+    };
+    if args.next().is_some() {
+        return usage(argv0);
+    }
+
+    let mut path = match env::var_os("RUNTIME_DIRECTORY") {
+        Some(v) => PathBuf::from(v),
+        None => Path::new("/run/wgconfd").join(ifname),
+    };
+    path.push(CONTROL_SOCKET);
+
+    let reply = match control::query(&path, "status") {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to query [{}]: {}", path.display(), e);
+            return 1;
+        }
+    };
+    match serde_json::from_slice::<serde_json::Value>(&reply) {
+        Ok(v) => {
+            println!("{}", serde_json::to_string_pretty(&v).unwrap());
+            0
+        }
+        Err(e) => {
+            eprintln!("Invalid reply: {}", e);
+            1
         }
     }
 }
@@ -295,6 +705,8 @@ fn run_check_source(argv0: &str, args: Vec<OsString>) -> i32 {
 }
 
 fn main() {
+    logging::init();
+
     let mut iter_args = env::args_os();
     let argv0 = iter_args.next().unwrap();
     let argv0 = argv0.to_string_lossy();
@@ -309,6 +721,9 @@ fn main() {
         } else if arg == "-h" || arg == "--help" {
             run = help;
             break;
+        } else if arg == "--status" {
+            run = run_status;
+            break;
         } else if arg == "--check-source" {
             run = run_check_source;
             break;
@@ -2,26 +2,36 @@
 //
 // Copyright 2019 Hristo Venev
 
+use super::failover::Failover;
+use super::resolver::Resolver;
 use super::Source;
 use crate::{config, model, proto};
-use std::collections::hash_map;
+use std::collections::{hash_map, HashMap};
 use std::{error, fmt};
 
 #[derive(Debug)]
 pub struct Error {
+    // The position of the source in the manager.
+    pub src_index: usize,
     pub src: String,
     pub peer: model::Key,
+    // The name of the peer from the source, if any.
+    pub name: Option<String>,
     important: bool,
     err: &'static str,
+    action: config::ErrorPolicy,
 }
 
 impl Error {
-    fn new(err: &'static str, src: &Source, p: &proto::Peer, important: bool) -> Self {
+    fn new(err: &'static str, i: usize, src: &Source, p: &proto::Peer, important: bool) -> Self {
         Self {
+            src_index: i,
             src: src.config.name.clone(),
             peer: p.public_key,
+            name: p.name.clone(),
             important,
             err,
+            action: config::ErrorPolicy::Apply,
         }
     }
 
@@ -29,6 +39,22 @@ impl Error {
     pub fn important(&self) -> bool {
         self.important
     }
+
+    #[inline]
+    pub fn message(&self) -> &'static str {
+        self.err
+    }
+
+    // What was done about the error.
+    #[inline]
+    pub fn action(&self) -> config::ErrorPolicy {
+        self.action
+    }
+
+    #[inline]
+    pub(super) fn set_action(&mut self, action: config::ErrorPolicy) {
+        self.action = action;
+    }
 }
 
 impl error::Error for Error {}
@@ -36,53 +62,120 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{} [{}]/[{}]: {}",
+            "{} [{}]/{}: {}",
             if self.important {
                 "invalid peer"
             } else {
                 "misconfigured peer"
             },
             self.src,
-            self.peer,
+            super::peer_label(&self.peer, self.name.as_deref()),
             self.err
         )
     }
 }
 
+// A peer left out because of the tag filters of its source.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Skipped {
+    pub src: String,
+    pub peer: model::Key,
+    pub name: Option<String>,
+}
+
+impl Skipped {
+    fn new(src: &Source, p: &proto::Peer) -> Self {
+        Self {
+            src: src.config.name.clone(),
+            peer: p.public_key,
+            name: p.name.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}]/{}",
+            self.src,
+            super::peer_label(&self.peer, self.name.as_deref())
+        )
+    }
+}
+
 struct PeerContact<'a> {
-    endpoint: Option<model::Endpoint>,
+    endpoint: Option<&'a model::HostEndpoint>,
     psk: Option<&'a model::Secret>,
     keepalive: u32,
 }
 
+// The peers that allowed IPs have been assigned to so far.
+#[derive(Default)]
+struct Claims {
+    ipv4: model::Ipv4Map<model::Key>,
+    ipv6: model::Ipv6Map<model::Key>,
+}
+
 pub(super) struct ConfigBuilder<'a> {
     c: model::Config,
     err: Vec<Error>,
+    skipped: Vec<Skipped>,
+    claims: Claims,
+    // The priority of the source that first defined each peer.
+    defined: HashMap<model::Key, i32>,
     public_key: model::Key,
     gc: &'a config::GlobalConfig,
+    resolver: &'a Resolver,
+    failover: &'a Failover,
 }
 
 impl<'a> ConfigBuilder<'a> {
     #[inline]
-    pub fn new(public_key: model::Key, gc: &'a config::GlobalConfig) -> Self {
+    pub fn new(
+        public_key: model::Key,
+        gc: &'a config::GlobalConfig,
+        resolver: &'a Resolver,
+        failover: &'a Failover,
+    ) -> Self {
         Self {
             c: model::Config::empty(),
             err: vec![],
+            skipped: vec![],
+            claims: Claims::default(),
+            defined: HashMap::new(),
             public_key,
             gc,
+            resolver,
+            failover,
         }
     }
 
     #[inline]
-    pub fn build(self) -> (model::Config, Vec<Error>) {
-        (self.c, self.err)
+    pub fn build(self) -> (model::Config, Vec<Error>, Vec<Skipped>) {
+        (self.c, self.err, self.skipped)
+    }
+
+    // Returns whether the tag filters of the source let the peer through.
+    fn filter(&mut self, src: &Source, p: &proto::Peer) -> bool {
+        if src.config.wants_tags(&p.tags) {
+            return true;
+        }
+        self.skipped.push(Skipped::new(src, p));
+        false
     }
 
     #[inline]
-    pub fn add_server(&mut self, src: &Source, p: &proto::Server) {
+    pub fn add_server(&mut self, i: usize, src: &Source, p: &proto::Server) {
         let gc = self.gc;
+        if p.peer.public_key == self.public_key {
+            return;
+        }
+        if !self.filter(src, &p.peer) {
+            return;
+        }
 
-        let mut contact = match peer_contact(gc, src, &p.peer) {
+        let mut contact = match peer_contact(gc, i, src, &p.peer) {
             Ok(v) => v,
             Err(e) => {
                 self.err.push(e);
@@ -90,84 +183,151 @@ impl<'a> ConfigBuilder<'a> {
             }
         };
         if contact.endpoint.is_none() {
-            contact.endpoint = Some(p.endpoint);
+            let key = &p.peer.public_key;
+            contact.endpoint = Some(self.failover.endpoint(key, &p.endpoints));
         }
 
-        if p.peer.public_key == self.public_key {
+        if !self.merge(i, src, &p.peer) {
             return;
         }
 
-        let ent = insert_peer(&mut self.c, &mut self.err, src, &p.peer, contact);
-        add_peer(&mut self.err, ent, src, &p.peer)
+        let ent = insert_peer(&mut self.c, self.resolver, &p.peer, contact);
+        let owner = p.peer.public_key;
+        add_peer(
+            &mut self.err,
+            &mut self.claims,
+            gc,
+            owner,
+            ent,
+            i,
+            src,
+            &p.peer,
+        )
     }
 
     #[inline]
-    pub fn add_road_warrior(&mut self, src: &Source, p: &proto::RoadWarrior) {
-        let contact = match peer_contact(self.gc, src, &p.peer) {
-            Ok(v) => v,
-            Err(e) => {
-                self.err.push(e);
-                return;
-            }
-        };
-
+    pub fn add_road_warrior(&mut self, i: usize, src: &Source, p: &proto::RoadWarrior) {
         if p.peer.public_key == self.public_key {
             self.err.push(Error::new(
                 "the local peer cannot be a road warrior",
+                i,
                 src,
                 &p.peer,
                 true,
             ));
             return;
         }
+        if !self.filter(src, &p.peer) {
+            return;
+        }
+
+        let contact = match peer_contact(self.gc, i, src, &p.peer) {
+            Ok(v) => v,
+            Err(e) => {
+                self.err.push(e);
+                return;
+            }
+        };
 
-        let ent = if p.base == self.public_key {
+        // A base talks to the road warrior directly, wherever it is listed.
+        // Everyone else goes through the first base that is alive.
+        let base = if p.bases.contains(&self.public_key) {
+            None
+        } else {
+            let c = &self.c;
+            match self.failover.base(&p.bases, |k| c.peers.contains_key(k)) {
+                Some(base) => Some(*base),
+                None => {
+                    self.err
+                        .push(Error::new("unknown base peer", i, src, &p.peer, true));
+                    return;
+                }
+            }
+        };
+
+        let owner = base.unwrap_or(p.peer.public_key);
+        let ent = if let Some(base) = base {
+            self.c.peers.get_mut(&base).unwrap()
+        } else {
             if !src.config.allow_road_warriors {
                 self.err.push(Error::new(
                     "road warriors from this source not allowed",
+                    i,
                     src,
                     &p.peer,
                     true,
                 ));
                 return;
             }
-            insert_peer(&mut self.c, &mut self.err, src, &p.peer, contact)
-        } else if let Some(ent) = self.c.peers.get_mut(&p.base) {
-            ent
-        } else {
-            self.err
-                .push(Error::new("unknown base peer", src, &p.peer, true));
-            return;
+            if !self.merge(i, src, &p.peer) {
+                return;
+            }
+            insert_peer(&mut self.c, self.resolver, &p.peer, contact)
+        };
+        let gc = self.gc;
+        add_peer(
+            &mut self.err,
+            &mut self.claims,
+            gc,
+            owner,
+            ent,
+            i,
+            src,
+            &p.peer,
+        )
+    }
+
+    // Returns whether to use a definition of a peer that may have been
+    // defined by another source already.
+    fn merge(&mut self, i: usize, src: &Source, p: &proto::Peer) -> bool {
+        let priority = src.config.priority;
+        let first = match self.defined.entry(p.public_key) {
+            hash_map::Entry::Occupied(ent) => *ent.get(),
+            hash_map::Entry::Vacant(ent) => {
+                ent.insert(priority);
+                return true;
+            }
         };
-        add_peer(&mut self.err, ent, src, &p.peer)
+        match self.gc.merge {
+            config::MergePolicy::Union => true,
+            config::MergePolicy::PriorityWins if first > priority => {
+                self.err.push(Error::new(
+                    "overridden by higher-priority source",
+                    i,
+                    src,
+                    p,
+                    false,
+                ));
+                false
+            }
+            _ => {
+                self.err
+                    .push(Error::new("duplicate public key", i, src, p, true));
+                true
+            }
+        }
     }
 }
 
 #[inline]
 fn insert_peer<'b>(
     c: &'b mut model::Config,
-    err: &mut Vec<Error>,
-    src: &Source,
+    resolver: &Resolver,
     p: &proto::Peer,
     contact: PeerContact<'_>,
 ) -> &'b mut model::Peer {
-    match c.peers.entry(p.public_key) {
-        hash_map::Entry::Occupied(ent) => {
-            err.push(Error::new("duplicate public key", src, p, true));
-            ent.into_mut()
-        }
-        hash_map::Entry::Vacant(ent) => ent.insert(model::Peer {
-            endpoint: contact.endpoint,
-            psk: contact.psk.cloned(),
-            keepalive: contact.keepalive,
-            ipv4: vec![],
-            ipv6: vec![],
-        }),
-    }
+    c.peers.entry(p.public_key).or_insert_with(|| model::Peer {
+        endpoint: contact.endpoint.and_then(|e| resolver.endpoint(e)),
+        psk: contact.psk.cloned(),
+        keepalive: contact.keepalive,
+        ipv4: vec![],
+        ipv6: vec![],
+    })
 }
 
 fn peer_contact<'a>(
     gc: &'a config::GlobalConfig,
+    i: usize,
     src: &'a Source,
     p: &proto::Peer,
 ) -> Result<PeerContact<'a>, Error> {
@@ -180,11 +340,11 @@ fn peer_contact<'a>(
     if let Some(pc) = gc.peers.get(&p.public_key) {
         if let Some(ref want_src) = &pc.source {
             if *want_src != src.config.name {
-                return Err(Error::new("peer source not allowed", src, p, true));
+                return Err(Error::new("peer source not allowed", i, src, p, true));
             }
         }
 
-        if let Some(endpoint) = pc.endpoint {
+        if let Some(ref endpoint) = pc.endpoint {
             r.endpoint = Some(endpoint);
         }
 
@@ -200,25 +360,61 @@ fn peer_contact<'a>(
     Ok(r)
 }
 
-fn add_peer(err: &mut Vec<Error>, ent: &mut model::Peer, src: &Source, p: &proto::Peer) {
+#[allow(clippy::too_many_arguments)]
+fn add_peer(
+    err: &mut Vec<Error>,
+    claims: &mut Claims,
+    gc: &config::GlobalConfig,
+    owner: model::Key,
+    ent: &mut model::Peer,
+    i: usize,
+    src: &Source,
+    p: &proto::Peer,
+) {
     let mut added = false;
     let mut removed = false;
+    let mut overlap = false;
+    let keep_first = gc.on_overlap == config::OverlapPolicy::KeepFirst;
 
-    for i in &p.ipv4 {
-        if src.config.ipv4.contains(i) {
-            ent.ipv4.push(*i);
-            added = true;
-        } else {
+    for net in &p.ipv4 {
+        if !src.config.ipv4.contains(net) {
             removed = true;
+            continue;
         }
+        if claims
+            .ipv4
+            .overlapping(net)
+            .iter()
+            .any(|(_, k)| **k != owner)
+        {
+            overlap = true;
+            if keep_first {
+                continue;
+            }
+        }
+        claims.ipv4.insert(*net, owner);
+        ent.ipv4.push(*net);
+        added = true;
     }
-    for i in &p.ipv6 {
-        if src.config.ipv6.contains(i) {
-            ent.ipv6.push(*i);
-            added = true;
-        } else {
+    for net in &p.ipv6 {
+        if !src.config.ipv6.contains(net) {
             removed = true;
+            continue;
         }
+        if claims
+            .ipv6
+            .overlapping(net)
+            .iter()
+            .any(|(_, k)| **k != owner)
+        {
+            overlap = true;
+            if keep_first {
+                continue;
+            }
+        }
+        claims.ipv6.insert(*net, owner);
+        ent.ipv6.push(*net);
+        added = true;
     }
 
     if removed {
@@ -227,6 +423,15 @@ fn add_peer(err: &mut Vec<Error>, ent: &mut model::Peer, src: &Source, p: &proto
         } else {
             "all IPs removed"
         };
-        err.push(Error::new(msg, src, p, !added));
+        err.push(Error::new(msg, i, src, p, !added));
+    }
+    if overlap && gc.on_overlap != config::OverlapPolicy::Ignore {
+        err.push(Error::new(
+            "allowed IPs overlap with another peer",
+            i,
+            src,
+            p,
+            keep_first,
+        ));
     }
 }
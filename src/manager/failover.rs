@@ -0,0 +1,300 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use crate::model;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+// How often to look for handshakes with bases that are not alive, so that road
+// warriors move back soon after their preferred base recovers.
+pub const RECHECK: Duration = Duration::from_secs(10);
+
+// Chooses between the candidate endpoints of servers that have several, and
+// between the bases of road warriors that have several.
+#[derive(Default)]
+pub struct Failover {
+    // The candidate in use and when it was chosen.
+    current: HashMap<model::Key, (model::HostEndpoint, SystemTime)>,
+    // The last candidate a handshake succeeded with.
+    working: HashMap<model::Key, model::HostEndpoint>,
+    // The bases with a recent handshake, or `None` if unknown.
+    alive: Option<HashSet<model::Key>>,
+}
+
+impl Failover {
+    // Starts with the candidates known to have worked before.
+    #[inline]
+    pub fn new(working: HashMap<model::Key, model::HostEndpoint>) -> Self {
+        Self {
+            current: HashMap::new(),
+            working,
+            alive: None,
+        }
+    }
+
+    #[inline]
+    pub fn working(&self) -> &HashMap<model::Key, model::HostEndpoint> {
+        &self.working
+    }
+
+    // The candidate to use for a server. The one in use is kept as long as the
+    // server still lists it.
+    pub fn endpoint<'a>(
+        &self,
+        key: &model::Key,
+        candidates: &'a [model::HostEndpoint],
+    ) -> &'a model::HostEndpoint {
+        let current = self.current.get(key).map(|(e, _)| e);
+        let find = |e: Option<&model::HostEndpoint>| candidates.iter().find(|c| Some(*c) == e);
+        find(current)
+            .or_else(|| find(self.working.get(key)))
+            .unwrap_or(&candidates[0])
+    }
+
+    // The base to route a road warrior through: the first configured one that
+    // is alive, or just the first configured one if none are.
+    pub fn base<'a>(
+        &self,
+        bases: &'a [model::Key],
+        configured: impl Fn(&model::Key) -> bool,
+    ) -> Option<&'a model::Key> {
+        let mut candidates = bases.iter().filter(|k| configured(k));
+        let first = candidates.clone().next();
+        match self.alive {
+            Some(ref alive) => candidates.find(|k| alive.contains(k)).or(first),
+            None => first,
+        }
+    }
+
+    // Records which of `bases` have had a handshake within `stale_after`, or
+    // forgets it if `handshakes` is `None`. Returns when the next one becomes
+    // stale, or when to check the others again if sooner.
+    pub fn set_alive(
+        &mut self,
+        bases: HashSet<model::Key>,
+        handshakes: Option<&HashMap<model::Key, SystemTime>>,
+        stale_after: Duration,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        let handshakes = match handshakes {
+            Some(v) if !bases.is_empty() => v,
+            _ => {
+                self.alive = None;
+                return None;
+            }
+        };
+
+        let mut alive = HashSet::new();
+        let mut next: Option<SystemTime> = None;
+        for key in bases {
+            let t = match handshakes.get(&key).map(|t| *t + stale_after) {
+                Some(t) if t > now => {
+                    alive.insert(key);
+                    t
+                }
+                _ => now + RECHECK,
+            };
+            next = Some(next.map_or(t, |v| v.min(t)));
+        }
+
+        if let Some(ref old) = self.alive {
+            for key in old.difference(&alive) {
+                log!(
+                    4,
+                    "base_stale",
+                    { peer: key },
+                    "No recent handshake with base [{}], moving its road warriors",
+                    key
+                );
+            }
+            for key in alive.difference(old) {
+                log!(
+                    5,
+                    "base_alive",
+                    { peer: key },
+                    "Base [{}] is reachable again",
+                    key
+                );
+            }
+        }
+        self.alive = Some(alive);
+        next
+    }
+
+    // Switches to the next candidate for servers that have not had a handshake
+    // within `stale_after` since their current candidate was chosen. Servers
+    // come with their keepalive, or `None` if they are not configured. Without
+    // a keepalive an idle server never handshakes, so it is left alone.
+    // Returns whether the working candidates changed and when to check again.
+    pub fn update<'a>(
+        &mut self,
+        servers: impl IntoIterator<Item = (model::Key, &'a [model::HostEndpoint], Option<u32>)>,
+        handshakes: &HashMap<model::Key, SystemTime>,
+        stale_after: Duration,
+        now: SystemTime,
+    ) -> (bool, Option<SystemTime>) {
+        let mut current = HashMap::new();
+        let mut working = HashMap::new();
+        let mut next: Option<SystemTime> = None;
+        let mut wake = |t: SystemTime| next = Some(next.map_or(t, |v| v.min(t)));
+
+        for (key, candidates, keepalive) in servers {
+            if candidates.len() < 2 || current.contains_key(&key) {
+                continue;
+            }
+            let endpoint = self.endpoint(&key, candidates).clone();
+            let since = match self.current.get(&key) {
+                Some((e, since)) if *e == endpoint => *since,
+                _ => now,
+            };
+            if let Some(e) = self.working.get(&key) {
+                working.insert(key, e.clone());
+            }
+            let keepalive = match keepalive {
+                Some(v) => v,
+                None => {
+                    current.insert(key, (endpoint, since));
+                    continue;
+                }
+            };
+
+            let t = handshakes.get(&key).map(|t| *t + stale_after);
+            if let Some(t) = t.filter(|t| *t > now) {
+                wake(t);
+                working.insert(key, endpoint.clone());
+                current.insert(key, (endpoint, since));
+                continue;
+            }
+            if keepalive == 0 {
+                current.insert(key, (endpoint, now));
+                continue;
+            }
+            if now < since + stale_after {
+                wake(since + stale_after);
+                current.insert(key, (endpoint, since));
+                continue;
+            }
+
+            let i = candidates.iter().position(|c| *c == endpoint).unwrap();
+            let endpoint = candidates[(i + 1) % candidates.len()].clone();
+            log!(
+                4,
+                "endpoint_failover",
+                { peer: &key, endpoint: endpoint.to_string() },
+                "No recent handshake with [{}], trying endpoint {}",
+                key,
+                endpoint
+            );
+            wake(now + stale_after);
+            current.insert(key, (endpoint, now));
+        }
+
+        let changed = working != self.working;
+        self.current = current;
+        self.working = working;
+        (changed, next)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Failover, RECHECK};
+    use crate::model::{HostEndpoint, Key};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_update() {
+        let key = Key::from_bytes([2; 32]);
+        let candidates: Vec<HostEndpoint> = ["192.0.2.2:656", "[2001:db8::2]:656"]
+            .iter()
+            .map(|v| HostEndpoint::from_str(v).unwrap())
+            .collect();
+        let servers = || vec![(key, &candidates[..], Some(25))];
+        let secs = Duration::from_secs;
+        let t0 = SystemTime::now();
+        let mut handshakes = HashMap::new();
+
+        let mut f = Failover::new(HashMap::new());
+        assert_eq!(f.endpoint(&key, &candidates), &candidates[0]);
+
+        // A new candidate gets some time.
+        let r = f.update(servers(), &handshakes, secs(180), t0);
+        assert_eq!(r, (false, Some(t0 + secs(180))));
+        assert_eq!(f.endpoint(&key, &candidates), &candidates[0]);
+
+        let t1 = t0 + secs(180);
+        let r = f.update(servers(), &handshakes, secs(180), t1);
+        assert_eq!(r, (false, Some(t1 + secs(180))));
+        assert_eq!(f.endpoint(&key, &candidates), &candidates[1]);
+
+        // A handshake confirms the candidate.
+        handshakes.insert(key, t1 + secs(5));
+        let r = f.update(servers(), &handshakes, secs(180), t1 + secs(10));
+        assert_eq!(r, (true, Some(t1 + secs(185))));
+        assert_eq!(f.working()[&key], candidates[1]);
+
+        // The working candidate is used first after a restart.
+        let mut f = Failover::new(f.working().clone());
+        assert_eq!(f.endpoint(&key, &candidates), &candidates[1]);
+        assert_eq!(f.endpoint(&key, &candidates[..1]), &candidates[0]);
+
+        // Idle servers without a keepalive are left alone.
+        let idle = || vec![(key, &candidates[..], Some(0))];
+        let t2 = t1 + secs(1000);
+        assert_eq!(f.update(idle(), &handshakes, secs(180), t2), (false, None));
+        let t3 = t2 + secs(1000);
+        assert_eq!(f.update(idle(), &handshakes, secs(180), t3), (false, None));
+        assert_eq!(f.endpoint(&key, &candidates), &candidates[1]);
+    }
+
+    #[test]
+    fn test_base() {
+        let (k2, k3, k4) = (
+            Key::from_bytes([2; 32]),
+            Key::from_bytes([3; 32]),
+            Key::from_bytes([4; 32]),
+        );
+        let bases = [k2, k3, k4];
+        let secs = Duration::from_secs;
+        let t = SystemTime::now();
+        let all = |_: &Key| true;
+
+        let mut f = Failover::default();
+        assert_eq!(f.base(&bases, all), Some(&k2));
+        assert_eq!(f.base(&bases, |k| *k != k2), Some(&k3));
+        assert_eq!(f.base(&bases, |_| false), None);
+
+        let mut handshakes = HashMap::new();
+        handshakes.insert(k2, t - secs(200));
+        handshakes.insert(k4, t - secs(100));
+        let r = f.set_alive(
+            bases.iter().cloned().collect(),
+            Some(&handshakes),
+            secs(180),
+            t,
+        );
+        assert_eq!(r, Some(t + RECHECK));
+        assert_eq!(f.base(&bases, all), Some(&k4));
+        assert_eq!(f.base(&bases, |k| *k != k4), Some(&k2));
+
+        handshakes.insert(k2, t - secs(10));
+        handshakes.insert(k3, t - secs(20));
+        let r = f.set_alive(
+            bases.iter().cloned().collect(),
+            Some(&handshakes),
+            secs(180),
+            t,
+        );
+        assert_eq!(r, Some(t + secs(80)));
+        assert_eq!(f.base(&bases, all), Some(&k2));
+
+        assert_eq!(
+            f.set_alive(bases.iter().cloned().collect(), None, secs(180), t),
+            None
+        );
+        assert_eq!(f.base(&bases, all), Some(&k2));
+    }
+}
@@ -2,49 +2,130 @@
 //
 // Copyright 2019 Hristo Venev
 
-use crate::{config, fileutil, model, proto, wg};
+use crate::{config, fileutil, logging, model, proto, watch, wg};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
-use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant, SystemTime};
+use std::{cmp, io, iter, mem, ptr};
 
 struct Source {
     config: config::Source,
     data: proto::Source,
+    validators: updater::Validators,
     next_update: Instant,
+    // The delay before the retry after the last failed fetch.
     backoff: Option<Duration>,
+    // The result of the last fetch.
+    last_update: Option<SystemTime>,
+    last_error: Option<String>,
+    from_cache: bool,
+    fetch_successes: u64,
+    fetch_failures: u64,
+    // The configuration last applied, kept only for `reject_source`.
+    accepted: Option<proto::SourceConfig>,
+}
+
+impl Source {
+    fn new(config: config::Source) -> Self {
+        Self {
+            config,
+            data: proto::Source::empty(),
+            validators: updater::Validators::default(),
+            next_update: Instant::now(),
+            backoff: None,
+            last_update: None,
+            last_error: None,
+            from_cache: false,
+            fetch_successes: 0,
+            fetch_failures: 0,
+            accepted: None,
+        }
+    }
+
+    // The local files the source is read from, including detached signatures.
+    fn local_files(&self) -> Vec<PathBuf> {
+        let mut r = vec![];
+        for url in &self.config.urls {
+            r.extend(watch::file_url_path(url));
+            if self.config.verify_key.is_some() && self.config.signature_url.is_none() {
+                r.extend(watch::file_url_path(&format!("{}.minisig", url)));
+            }
+        }
+        if self.config.verify_key.is_some() {
+            if let Some(ref url) = self.config.signature_url {
+                r.extend(watch::file_url_path(url));
+            }
+        }
+        r
+    }
 }
 
 mod updater;
 pub use updater::load_source;
 
 mod builder;
+mod failover;
+mod resolver;
+
+#[cfg(test)]
+mod test;
 
 pub struct Manager {
-    dev: wg::Device,
+    dev: Box<dyn wg::Device>,
     global_config: config::GlobalConfig,
     sources: Vec<Source>,
     current: model::Config,
     state_path: PathBuf,
     updater: updater::Updater,
+    next_reconcile: Instant,
+    // The problems with the last computed configuration.
+    errors: Vec<builder::Error>,
+    // The peers left out of it by tag filters.
+    skipped: Vec<builder::Skipped>,
+    last_change: Option<SystemTime>,
+    // The last configuration refused because of `reject_update`.
+    rejected: Option<model::Config>,
+    resolver: resolver::Resolver,
+    failover: failover::Failover,
+}
+
+// The contents of `state.json`.
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct State<'a> {
+    #[serde(flatten)]
+    config: Cow<'a, model::Config>,
+    // The endpoints last known to work for servers that have several.
+    #[serde(default)]
+    endpoints: Cow<'a, HashMap<model::Key, model::HostEndpoint>>,
 }
 
 impl Manager {
     pub fn new(ifname: OsString, c: config::Config) -> io::Result<Self> {
-        let runtime_directory = c.runtime_directory.ok_or_else(|| {
-            io::Error::new(io::ErrorKind::InvalidInput, "runtime directory required")
-        })?;
+        let runtime_directory = runtime_directory(&c)?;
+        let dev = wg::open(ifname, runtime_directory)?;
+        Self::with_device(dev, c)
+    }
 
-        let mut state_path = runtime_directory.clone();
+    pub fn with_device(dev: Box<dyn wg::Device>, c: config::Config) -> io::Result<Self> {
+        let mut state_path = runtime_directory(&c)?;
         state_path.push("state.json");
 
         let mut m = Self {
-            dev: wg::Device::open(ifname, runtime_directory)?,
+            dev,
             global_config: c.global,
             sources: vec![],
             current: model::Config::empty(),
             state_path,
             updater: updater::Updater::new(c.updater),
+            next_reconcile: Instant::now(),
+            errors: vec![],
+            skipped: vec![],
+            last_change: None,
+            rejected: None,
+            resolver: resolver::Resolver::new(),
+            failover: failover::Failover::default(),
         };
 
         let _ = m.current_load();
@@ -61,48 +142,73 @@ impl Manager {
             Ok(data) => data,
             Err(e) => {
                 if e.kind() != io::ErrorKind::NotFound {
-                    eprintln!("<3>Failed to read interface state: {}", e);
+                    log!(
+                        3,
+                        "state_load_failed",
+                        { error: e.to_string() },
+                        "Failed to read interface state: {}",
+                        e
+                    );
                 }
                 return false;
             }
         };
 
         let mut de = serde_json::Deserializer::from_slice(&data);
-        match serde::Deserialize::deserialize(&mut de) {
-            Ok(c) => {
-                self.current = c;
+        match <State<'_> as serde::Deserialize>::deserialize(&mut de) {
+            Ok(state) => {
+                self.current = state.config.into_owned();
+                self.failover = failover::Failover::new(state.endpoints.into_owned());
                 true
             }
             Err(e) => {
-                eprintln!("<3>Failed to load interface state: {}", e);
+                log!(
+                    3,
+                    "state_load_failed",
+                    { error: e.to_string() },
+                    "Failed to load interface state: {}",
+                    e
+                );
                 false
             }
         }
     }
 
-    fn current_update(&mut self, c: &model::Config) -> io::Result<()> {
-        let data = serde_json::to_vec(c)?;
+    fn current_update(&self) -> io::Result<()> {
+        let data = serde_json::to_vec(&State {
+            config: Cow::Borrowed(&self.current),
+            endpoints: Cow::Borrowed(self.failover.working()),
+        })?;
         fileutil::update(&self.state_path, &data)
     }
 
+    fn save_state(&self) {
+        if let Err(e) = self.current_update() {
+            log!(
+                3,
+                "state_save_failed",
+                { error: e.to_string() },
+                "Failed to persist interface state: {}",
+                e
+            );
+        }
+    }
+
     fn add_source(&mut self, config: config::Source) -> io::Result<()> {
-        let mut s = Source {
-            config,
-            data: proto::Source::empty(),
-            next_update: Instant::now(),
-            backoff: None,
-        };
+        let mut s = Source::new(config);
 
         self.init_source(&mut s)?;
         self.sources.push(s);
         Ok(())
     }
 
-    fn init_source(&mut self, s: &mut Source) -> io::Result<()> {
+    fn init_source(&self, s: &mut Source) -> io::Result<()> {
+        // The cached serial is the one fetched sources are checked against.
+        let cached = s.config.monotonic_serial && self.updater.cache_load(s);
         if self.updater.update(s).0 {
             return Ok(());
         }
-        if self.updater.cache_load(s) {
+        if cached || self.updater.cache_load(s) {
             return Ok(());
         }
         if !s.config.required {
@@ -114,52 +220,381 @@ impl Manager {
         if self.updater.update(s).0 {
             return Ok(());
         }
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("failed to update required source [{}]", &s.config.url),
-        ))
+        Err(io::Error::other(format!(
+            "failed to update required source [{}]",
+            &s.config.name
+        )))
     }
 
+    // Switches to a new configuration. Sources whose configuration has not
+    // changed keep their data. The runtime directory cannot be changed.
+    pub fn reload(&mut self, c: config::Config) -> io::Result<()> {
+        let updater = mem::replace(&mut self.updater, updater::Updater::new(c.updater));
+
+        let mut added = vec![];
+        let mut kept = vec![false; self.sources.len()];
+        for cfg in c.sources {
+            let i = (0..self.sources.len()).find(|&i| !kept[i] && self.sources[i].config == cfg);
+            if let Some(i) = i {
+                kept[i] = true;
+                added.push(Err(i));
+                continue;
+            }
+            let mut s = Source::new(cfg);
+            if let Err(e) = self.init_source(&mut s) {
+                self.updater = updater;
+                return Err(e);
+            }
+            added.push(Ok(s));
+        }
+
+        let mut old: Vec<Option<Source>> = self.sources.drain(..).map(Some).collect();
+        for s in added {
+            let s = match s {
+                Ok(s) => {
+                    let name = &s.config.name;
+                    if old.iter().flatten().any(|o| o.config.name == *name) {
+                        log!(
+                            5,
+                            "source_reconfigured",
+                            { source: name },
+                            "Reconfigured source [{}]",
+                            name
+                        );
+                    } else {
+                        log!(5, "source_added", { source: name }, "Added source [{}]", name);
+                    }
+                    s
+                }
+                Err(i) => old[i].take().unwrap(),
+            };
+            self.sources.push(s);
+        }
+        for s in old.into_iter().flatten() {
+            if !self.sources.iter().any(|n| n.config.name == s.config.name) {
+                log!(
+                    5,
+                    "source_removed",
+                    { source: &s.config.name },
+                    "Removed source [{}]",
+                    &s.config.name
+                );
+            }
+        }
+
+        self.global_config = c.global;
+        Ok(())
+    }
+
+    #[inline]
+    fn error_policy(&self, src: &Source) -> config::ErrorPolicy {
+        src.config.on_error.unwrap_or(self.global_config.on_error)
+    }
+
+    // Also returns the peers left out by tag filters and the source
+    // configurations to remember for `reject_source`.
     fn make_config(
         &self,
         public_key: model::Key,
         ts: SystemTime,
-    ) -> (model::Config, Vec<builder::Error>, SystemTime) {
+    ) -> (
+        model::Config,
+        Vec<builder::Error>,
+        Vec<builder::Skipped>,
+        Vec<Option<proto::SourceConfig>>,
+        SystemTime,
+    ) {
         let mut t_cfg = ts + Duration::from_secs(1 << 20);
         let mut sources: Vec<(&Source, &proto::SourceConfig)> = vec![];
         for src in &self.sources {
-            let sc = src
-                .data
-                .next
-                .as_ref()
-                .and_then(|next| {
-                    if ts >= next.0 {
-                        Some(&next.1)
-                    } else {
-                        t_cfg = t_cfg.min(next.0);
-                        None
-                    }
-                })
-                .unwrap_or(&src.data.config);
+            let (sc, t_next) = src.data.config_at(ts);
+            if let Some(t) = t_next {
+                t_cfg = t_cfg.min(t);
+            }
             sources.push((src, sc));
         }
+        let fresh: Vec<_> = sources.iter().map(|(_, sc)| *sc).collect();
 
-        let mut cfg = builder::ConfigBuilder::new(public_key, &self.global_config);
+        // Sources with a higher priority go first, the rest in order.
+        let mut order: Vec<usize> = (0..sources.len()).collect();
+        order.sort_by_key(|i| cmp::Reverse(sources[*i].0.config.priority));
 
-        for (src, sc) in &sources {
-            for peer in &sc.servers {
-                cfg.add_server(src, peer);
+        // Invalid peers are left out and invalid sources replaced until the
+        // configuration only contains errors that are to be applied.
+        let mut dropped: HashSet<(usize, model::Key)> = HashSet::new();
+        let mut handled = vec![];
+        let (cfg, errs, skipped) = loop {
+            let mut cfg = builder::ConfigBuilder::new(
+                public_key,
+                &self.global_config,
+                &self.resolver,
+                &self.failover,
+            );
+
+            for &i in &order {
+                let (src, sc) = &sources[i];
+                for peer in &sc.servers {
+                    if let Some(t) = peer.peer.next_change(ts) {
+                        t_cfg = t_cfg.min(t);
+                    }
+                    if peer.peer.valid_at(ts) && !dropped.contains(&(i, peer.peer.public_key)) {
+                        cfg.add_server(i, src, peer);
+                    }
+                }
+            }
+
+            for &i in &order {
+                let (src, sc) = &sources[i];
+                for peer in &sc.road_warriors {
+                    if let Some(t) = peer.peer.next_change(ts) {
+                        t_cfg = t_cfg.min(t);
+                    }
+                    if peer.peer.valid_at(ts) && !dropped.contains(&(i, peer.peer.public_key)) {
+                        cfg.add_road_warrior(i, src, peer);
+                    }
+                }
+            }
+
+            let (cfg, mut errs, skipped) = cfg.build();
+
+            let mut retry = false;
+            let mut rejected = vec![false; sources.len()];
+            for err in &mut errs {
+                if !err.important() {
+                    continue;
+                }
+                let i = err.src_index;
+                let (src, sc) = sources[i];
+                let mut policy = self.error_policy(src);
+                if policy == config::ErrorPolicy::RejectSource {
+                    // Without a different configuration to fall back to, only
+                    // the invalid peers are left out.
+                    match src.accepted {
+                        Some(ref prev) if !ptr::eq(sc, prev) && sc != prev => {
+                            rejected[i] = true;
+                            retry = true;
+                        }
+                        _ => policy = config::ErrorPolicy::DropPeers,
+                    }
+                }
+                if policy == config::ErrorPolicy::DropPeers {
+                    retry |= dropped.insert((i, err.peer));
+                }
+                err.set_action(policy);
+            }
+
+            if !retry {
+                handled.append(&mut errs);
+                break (cfg, handled, skipped);
+            }
+
+            // Errors that are applied will show up again.
+            handled.extend(errs.into_iter().filter(|err| {
+                err.action() == config::ErrorPolicy::DropPeers
+                    || err.action() == config::ErrorPolicy::RejectSource
+            }));
+            for (i, (src, sc)) in sources.iter_mut().enumerate() {
+                if rejected[i] {
+                    // Fall back to the last applied configuration.
+                    *sc = src.accepted.as_ref().unwrap();
+                }
+            }
+        };
+
+        let accepted = sources
+            .iter()
+            .zip(fresh)
+            .map(|((src, sc), fresh)| {
+                if ptr::eq(*sc, fresh)
+                    && self.error_policy(src) == config::ErrorPolicy::RejectSource
+                    && src.accepted.as_ref() != Some(fresh)
+                {
+                    Some(fresh.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        (cfg, errs, skipped, accepted, t_cfg)
+    }
+
+    // The definitions of the peers in effect at `ts`, with their sources. Like
+    // in the configuration, the first definition of a peer wins.
+    fn peer_definitions(&self, ts: SystemTime) -> HashMap<model::Key, (&str, &proto::Peer)> {
+        let mut order: Vec<&Source> = self.sources.iter().collect();
+        order.sort_by_key(|src| cmp::Reverse(src.config.priority));
+
+        let mut r = HashMap::new();
+        for src in order {
+            let sc = src.data.config_at(ts).0;
+            let peers = sc
+                .servers
+                .iter()
+                .map(|p| &p.peer)
+                .chain(sc.road_warriors.iter().map(|p| &p.peer));
+            for p in peers {
+                r.entry(p.public_key).or_insert((&src.config.name[..], p));
+            }
+        }
+        r
+    }
+
+    // The hostname endpoints that may be used, with the peers using them.
+    fn host_endpoints(&self) -> Vec<(model::Key, resolver::Host)> {
+        let gc = &self.global_config;
+        let mut r = vec![];
+        let mut add = |key: model::Key, e: &model::HostEndpoint| {
+            if let model::HostEndpoint::Name(host, port) = e {
+                r.push((key, (host.clone(), *port)));
+            }
+        };
+
+        for (key, pc) in &gc.peers {
+            if let Some(ref e) = pc.endpoint {
+                add(*key, e);
+            }
+        }
+        for src in &self.sources {
+            let configs = iter::once(&src.data.config)
+                .chain(src.data.next.iter().map(|(_, sc)| sc))
+                .chain(&src.accepted);
+            for p in configs.flat_map(|sc| &sc.servers) {
+                let key = p.peer.public_key;
+                if gc.peers.get(&key).map_or(true, |pc| pc.endpoint.is_none()) {
+                    for e in &p.endpoints {
+                        add(key, e);
+                    }
+                }
+            }
+        }
+        r
+    }
+
+    // The latest handshakes, if anything depends on them. Peers without a
+    // recent handshake may have become unreachable at their current address.
+    fn handshakes(&self) -> Option<HashMap<model::Key, SystemTime>> {
+        if self.global_config.stale_handshake_sec == 0 {
+            return None;
+        }
+        let multi = self.sources.iter().any(|src| {
+            iter::once(&src.data.config)
+                .chain(src.data.next.iter().map(|(_, sc)| sc))
+                .any(|sc| {
+                    sc.servers.iter().any(|p| p.endpoints.len() > 1)
+                        || sc.road_warriors.iter().any(|p| p.bases.len() > 1)
+                })
+        });
+        if !multi && self.host_endpoints().is_empty() {
+            return None;
+        }
+        match self.dev.get_handshakes() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log!(
+                    4,
+                    "handshakes_failed",
+                    { error: e.to_string() },
+                    "Failed to read latest handshakes: {}",
+                    e
+                );
+                None
             }
         }
+    }
 
-        for (src, sc) in &sources {
-            for peer in &sc.road_warriors {
-                cfg.add_road_warrior(src, peer);
+    // Looks up the hostname endpoints that are due. Hostnames of peers without
+    // a recent handshake are looked up early, as their address may have
+    // changed. This includes peers that never handshake, which are looked up
+    // every `stale_handshake_sec`. Lookups block.
+    fn resolve(
+        &mut self,
+        sysnow: SystemTime,
+        handshakes: Option<&HashMap<model::Key, SystemTime>>,
+    ) -> Option<Instant> {
+        let hosts = self.host_endpoints();
+        let interval = secs_or_never(self.global_config.resolve_sec);
+        let stale_after = secs_or_never(self.global_config.stale_handshake_sec);
+        let now = Instant::now();
+
+        let mut stale = HashSet::new();
+        let mut t_stale: Option<Instant> = None;
+        if let Some(handshakes) = handshakes {
+            for (key, host) in &hosts {
+                if !self.current.peers.contains_key(key) {
+                    continue;
+                }
+                let t = handshakes.get(key).map(|t| *t + stale_after);
+                match t.and_then(|t| t.duration_since(sysnow).ok()) {
+                    Some(d) => {
+                        let t = now + d;
+                        t_stale = Some(t_stale.map_or(t, |v| v.min(t)));
+                    }
+                    None => {
+                        stale.insert(host.clone());
+                    }
+                }
             }
         }
 
-        let (cfg, errs) = cfg.build();
-        (cfg, errs, t_cfg)
+        let wanted = hosts.into_iter().map(|(_, host)| host).collect();
+        let t_resolve = self
+            .resolver
+            .update(wanted, &stale, interval, stale_after, now);
+        match (t_resolve, t_stale) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    // Moves servers with a keepalive but without a recent handshake to their
+    // next candidate endpoint, and road warriors to a base with a recent handshake. Returns
+    // whether the endpoints known to work have changed.
+    fn fail_over(
+        &mut self,
+        sysnow: SystemTime,
+        handshakes: Option<&HashMap<model::Key, SystemTime>>,
+    ) -> (bool, Option<SystemTime>) {
+        let gc = &self.global_config;
+        let current = &self.current;
+        let stale_after = secs_or_never(gc.stale_handshake_sec);
+
+        let bases = self
+            .sources
+            .iter()
+            .flat_map(|src| &src.data.config_at(sysnow).0.road_warriors)
+            .filter(|p| p.bases.len() > 1)
+            .flat_map(|p| p.bases.iter().cloned())
+            .collect();
+        let t_bases = self
+            .failover
+            .set_alive(bases, handshakes, stale_after, sysnow);
+
+        let handshakes = match handshakes {
+            Some(v) => v,
+            None => return (false, None),
+        };
+        let mut order: Vec<&Source> = self.sources.iter().collect();
+        order.sort_by_key(|src| cmp::Reverse(src.config.priority));
+        let servers = order
+            .into_iter()
+            .flat_map(|src| &src.data.config_at(sysnow).0.servers)
+            .filter(|p| {
+                let key = p.peer.public_key;
+                gc.peers.get(&key).map_or(true, |pc| pc.endpoint.is_none())
+            })
+            .map(|p| {
+                let key = p.peer.public_key;
+                let keepalive = current.peers.get(&key).map(|p| p.keepalive);
+                (key, &p.endpoints[..], keepalive)
+            });
+        let (changed, t_servers) = self
+            .failover
+            .update(servers, handshakes, stale_after, sysnow);
+        let t = match (t_bases, t_servers) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        (changed, t)
     }
 
     fn refresh(&mut self) -> io::Result<Instant> {
@@ -177,50 +612,425 @@ impl Manager {
         Ok(t_refresh)
     }
 
+    pub fn local_files(&self) -> Vec<PathBuf> {
+        let mut r = vec![];
+        for src in &self.sources {
+            for path in src.local_files() {
+                if !r.contains(&path) {
+                    r.push(path);
+                }
+            }
+        }
+        r
+    }
+
+    // Makes the sources that read `path` due for a refresh.
+    pub fn file_changed(&mut self, path: &Path) {
+        let now = Instant::now();
+        for src in &mut self.sources {
+            if src.local_files().iter().any(|p| p == path) {
+                log!(
+                    6,
+                    "source_file_changed",
+                    { source: &src.config.name, path: path },
+                    "Local file of [{}] changed",
+                    &src.config.name
+                );
+                src.next_update = now;
+            }
+        }
+    }
+
+    pub fn refresh_all(&mut self) {
+        let now = Instant::now();
+        for src in &mut self.sources {
+            src.next_update = now;
+        }
+        self.resolver.refresh_all();
+    }
+
+    pub fn dump_state(&self) {
+        let now = Instant::now();
+        let sysnow = SystemTime::now();
+        for src in &self.sources {
+            let mut line = format!("Source [{}]:", &src.config.name);
+            match src.last_update {
+                Some(t) => {
+                    let ago = sysnow.duration_since(t).unwrap_or(Duration::from_secs(0));
+                    line.push_str(&format!(" updated {:.0?} ago", ago));
+                }
+                None => line.push_str(" never updated"),
+            }
+            if let Some(ref e) = src.last_error {
+                line.push_str(&format!(", last error: {}", e));
+            }
+            let next = src.next_update.saturating_duration_since(now);
+            line.push_str(&format!(", next update in {:.0?}", next));
+            log!(6, "dump_source", { source: &src.config.name }, "{}", line);
+        }
+
+        let defs = self.peer_definitions(sysnow);
+        let mut peers: Vec<_> = self.current.peers.iter().collect();
+        peers.sort_by_key(|(k, _)| *k);
+        for (k, p) in peers {
+            let name = defs.get(k).and_then(|(_, def)| def.name.as_deref());
+            let mut line = format!("Peer [{}]:", k);
+            if let Some(name) = name {
+                line.push_str(&format!(" {},", name));
+            }
+            if let Some(ref endpoint) = p.endpoint {
+                line.push_str(&format!(" endpoint {},", endpoint));
+            }
+            if p.keepalive != 0 {
+                line.push_str(&format!(" keepalive {},", p.keepalive));
+            }
+            line.push_str(" allowed IPs");
+            if p.ipv4.is_empty() && p.ipv6.is_empty() {
+                line.push_str(" (none)");
+            }
+            for net in &p.ipv4 {
+                line.push_str(&format!(" {}", net));
+            }
+            for net in &p.ipv6 {
+                line.push_str(&format!(" {}", net));
+            }
+            log!(6, "dump_peer", { peer: k, peer_name: name }, "{}", line);
+        }
+    }
+
+    pub fn status(&self) -> proto::Status<'_> {
+        let now = Instant::now();
+        let sysnow = SystemTime::now();
+
+        let mut sources = vec![];
+        let mut scheduled = vec![];
+        for src in &self.sources {
+            sources.push(proto::SourceStatus {
+                name: &src.config.name,
+                urls: &src.config.urls,
+                serial: src.data.serial,
+                from_cache: src.from_cache,
+                last_update: src.last_update,
+                last_error: src.last_error.as_deref(),
+                fetch_successes: src.fetch_successes,
+                fetch_failures: src.fetch_failures,
+                backoff_sec: src.backoff.map(|b| b.as_secs_f64()),
+                next_update: sysnow + src.next_update.saturating_duration_since(now),
+            });
+            for (update_at, _) in &src.data.next {
+                if *update_at > sysnow {
+                    scheduled.push(proto::ScheduledUpdate {
+                        source: &src.config.name,
+                        update_at: *update_at,
+                    });
+                }
+            }
+        }
+
+        let peer_info = self
+            .peer_definitions(sysnow)
+            .into_iter()
+            .filter(|(k, (_, p))| {
+                self.current.peers.contains_key(k)
+                    && (p.name.is_some() || p.description.is_some() || !p.tags.is_empty())
+            })
+            .map(|(k, (source, p))| {
+                let info = proto::PeerInfo {
+                    source,
+                    name: p.name.as_deref(),
+                    description: p.description.as_deref(),
+                    tags: &p.tags,
+                };
+                (k, info)
+            })
+            .collect();
+
+        proto::Status {
+            sources,
+            config: (&self.current).into(),
+            peer_info,
+            scheduled,
+            errors: self
+                .errors
+                .iter()
+                .map(|e| proto::ErrorStatus {
+                    message: e.to_string(),
+                    source: e.src.clone(),
+                    peer: e.peer,
+                    peer_name: e.name.clone(),
+                    important: e.important(),
+                    action: e.action().as_str(),
+                })
+                .collect(),
+            last_change: self.last_change,
+        }
+    }
+
+    #[inline]
     pub fn update(&mut self) -> io::Result<Instant> {
-        let t_refresh = self.refresh()?;
+        self.update_at(SystemTime::now())
+    }
+
+    // Makes sure that the peers we manage are configured the way we last
+    // configured them. Endpoints are not checked because peers roam.
+    fn reconcile(&mut self) -> io::Result<()> {
+        let mut live = match self.dev.get_peers() {
+            Ok(v) => v,
+            Err(e) => {
+                log!(
+                    3,
+                    "reconcile_failed",
+                    { error: e.to_string() },
+                    "Failed to read interface state: {}",
+                    e
+                );
+                return Ok(());
+            }
+        };
+
+        let defs = self.peer_definitions(SystemTime::now());
+        let shared = SharedIps::new(&self.current);
+        let mut actual = model::Config::empty();
+        let mut drifted = false;
+        for (pubkey, want) in &self.current.peers {
+            let name = defs.get(pubkey).and_then(|(_, def)| def.name.as_deref());
+            let label = peer_label(pubkey, name);
+            let mut have = match live.peers.remove(pubkey) {
+                Some(v) => v,
+                None => {
+                    log!(
+                        4,
+                        "peer_missing",
+                        { peer: pubkey, peer_name: name },
+                        "Peer {} missing from interface",
+                        label
+                    );
+                    drifted = true;
+                    continue;
+                }
+            };
+            have.endpoint = want.endpoint;
+
+            let what = peer_drift(&have, want, &shared);
+            if what.is_empty() {
+                actual.peers.insert(*pubkey, want.clone());
+            } else {
+                log!(
+                    4,
+                    "peer_drifted",
+                    { peer: pubkey, peer_name: name, changed: what.join(",") },
+                    "Peer {} changed on interface: {}",
+                    label,
+                    what.join(", ")
+                );
+                drifted = true;
+                actual.peers.insert(*pubkey, have);
+            }
+        }
+
+        if drifted {
+            log!(5, "reconcile", "Restoring interface configuration");
+            self.dev.apply_diff(&actual, &self.current)?;
+        }
+        Ok(())
+    }
+
+    fn update_at(&mut self, sysnow: SystemTime) -> io::Result<Instant> {
+        let mut t_refresh = self.refresh()?;
+
+        let reconcile = self.global_config.reconcile_sec;
+        if reconcile != 0 {
+            let now = Instant::now();
+            if now >= self.next_reconcile {
+                self.reconcile()?;
+                self.next_reconcile = now + Duration::from_secs(u64::from(reconcile));
+            }
+            t_refresh = t_refresh.min(self.next_reconcile);
+        }
+
+        let handshakes = self.handshakes();
+        if let Some(t) = self.resolve(sysnow, handshakes.as_ref()) {
+            t_refresh = t_refresh.min(t);
+        }
+        let (save_state, t_failover) = self.fail_over(sysnow, handshakes.as_ref());
+        if let Some(t) = t_failover {
+            let now = Instant::now();
+            t_refresh = t_refresh.min(now + t.duration_since(sysnow).unwrap_or_default());
+        }
 
         let public_key = self.dev.get_public_key()?;
         let now = Instant::now();
-        let sysnow = SystemTime::now();
-        let (config, errors, t_cfg) = self.make_config(public_key, sysnow);
+        let (config, errors, skipped, accepted, t_cfg) = self.make_config(public_key, sysnow);
         let time_to_cfg = t_cfg
             .duration_since(sysnow)
             .unwrap_or(Duration::from_secs(0));
         let t_cfg = now + time_to_cfg;
 
-        if config != self.current {
-            if errors.is_empty() {
-                eprintln!("<5>Applying configuration update");
-            } else {
-                eprint!(
-                    "<{}>New update contains errors: ",
-                    if errors.iter().any(|err| err.important()) {
-                        '4'
-                    } else {
-                        '5'
-                    }
+        for s in &skipped {
+            if !self.skipped.contains(s) {
+                log!(
+                    5,
+                    "peer_skipped",
+                    { source: &s.src, peer: &s.peer, peer_name: &s.name },
+                    "Skipping peer {}: excluded by tag filters",
+                    s
                 );
-                for err in &errors {
-                    eprint!("{}; ", err);
+            }
+        }
+        self.skipped = skipped;
+
+        let reject = errors
+            .iter()
+            .any(|err| err.action() == config::ErrorPolicy::RejectUpdate);
+        if config != self.current && (!reject || self.rejected.as_ref() != Some(&config)) {
+            log_errors(&errors, reject);
+        }
+
+        if reject {
+            self.rejected = Some(config);
+        } else {
+            self.rejected = None;
+            for (src, sc) in self.sources.iter_mut().zip(accepted) {
+                if sc.is_some() {
+                    src.accepted = sc;
                 }
-                eprintln!("applying anyway");
             }
-            self.dev.apply_diff(&self.current, &config)?;
-            if let Err(e) = self.current_update(&config) {
-                eprintln!("<3>Failed to persist interface state: {}", e);
+            if config != self.current {
+                self.dev.apply_diff(&self.current, &config)?;
+                self.current = config;
+                self.last_change = Some(SystemTime::now());
+                self.save_state();
+            } else if save_state {
+                self.save_state();
             }
-            self.current = config;
         }
+        self.errors = errors;
 
         Ok(if t_cfg < t_refresh {
-            eprintln!("<6>Next configuration update after {:.1?}", time_to_cfg);
+            log!(
+                6,
+                "next_config",
+                { delay_sec: time_to_cfg.as_secs_f64() },
+                "Next configuration update after {:.1?}",
+                time_to_cfg
+            );
             t_cfg
         } else if t_refresh > now {
             t_refresh
         } else {
-            eprintln!("<4>Next refresh immediately?");
+            log!(4, "refresh_immediately", "Next refresh immediately?");
             now
         })
     }
 }
+
+fn log_errors(errors: &[builder::Error], reject: bool) {
+    let what = if reject {
+        "keeping the current configuration"
+    } else {
+        "applying anyway"
+    };
+    if errors.is_empty() {
+        log!(5, "config_apply", "Applying configuration update");
+    } else if logging::structured() {
+        for err in errors {
+            log!(
+                if err.important() { 4 } else { 5 },
+                "config_error",
+                {
+                    source: &err.src,
+                    peer: &err.peer,
+                    peer_name: &err.name,
+                    error: err.message(),
+                    important: err.important(),
+                    action: err.action().as_str(),
+                },
+                "{}; {}",
+                err,
+                what
+            );
+        }
+    } else {
+        let mut msg = String::new();
+        for err in errors {
+            match err.action() {
+                config::ErrorPolicy::Apply => msg.push_str(&format!("{}; ", err)),
+                action => msg.push_str(&format!("{} ({}); ", err, action.as_str())),
+            }
+        }
+        log!(
+            if errors.iter().any(|err| err.important()) {
+                4
+            } else {
+                5
+            },
+            "config_error",
+            "New update contains errors: {}{}",
+            msg,
+            what
+        );
+    }
+}
+
+// How to refer to a peer in messages.
+fn peer_label(key: &model::Key, name: Option<&str>) -> String {
+    match name {
+        Some(name) => name.to_owned(),
+        None => format!("[{}]", key),
+    }
+}
+
+// A zero interval means never.
+#[inline]
+fn secs_or_never(v: u32) -> Duration {
+    Duration::from_secs(if v == 0 { 1 << 20 } else { u64::from(v) })
+}
+
+fn runtime_directory(c: &config::Config) -> io::Result<PathBuf> {
+    c.runtime_directory
+        .clone()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "runtime directory required"))
+}
+
+// The allowed IPs given to more than one peer. WireGuard keeps each of them
+// only on the peer it was given to last, so the others never match.
+struct SharedIps {
+    ipv4: HashSet<model::Ipv4Net>,
+    ipv6: HashSet<model::Ipv6Net>,
+}
+
+impl SharedIps {
+    fn new(c: &model::Config) -> Self {
+        fn shared<T: Copy + Eq + std::hash::Hash>(ips: impl Iterator<Item = T>) -> HashSet<T> {
+            let mut seen = HashSet::new();
+            ips.filter(|ip| !seen.insert(*ip)).collect()
+        }
+        Self {
+            ipv4: shared(c.peers.values().flat_map(|p| p.ipv4.iter().copied())),
+            ipv6: shared(c.peers.values().flat_map(|p| p.ipv6.iter().copied())),
+        }
+    }
+}
+
+fn peer_drift(have: &model::Peer, want: &model::Peer, shared: &SharedIps) -> Vec<&'static str> {
+    fn unshared<T: Copy + Eq + std::hash::Hash>(ips: &[T], shared: &HashSet<T>) -> Vec<T> {
+        ips.iter()
+            .copied()
+            .filter(|ip| !shared.contains(ip))
+            .collect()
+    }
+
+    let mut r = vec![];
+    if have.psk != want.psk {
+        r.push("preshared key");
+    }
+    if have.keepalive != want.keepalive {
+        r.push("keepalive");
+    }
+    let ipv4 = |ips: &[model::Ipv4Net]| model::Ipv4Set::from(&unshared(ips, &shared.ipv4)[..]);
+    let ipv6 = |ips: &[model::Ipv6Net]| model::Ipv6Set::from(&unshared(ips, &shared.ipv6)[..]);
+    if ipv4(&have.ipv4) != ipv4(&want.ipv4) || ipv6(&have.ipv6) != ipv6(&want.ipv6) {
+        r.push("allowed IPs");
+    }
+    r
+}
@@ -0,0 +1,225 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use crate::model;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+pub type Lookup = Box<dyn Fn(&str, u16) -> io::Result<Vec<SocketAddr>>>;
+
+pub type Host = (String, u16);
+
+// How long to wait before retrying a failed lookup.
+const RETRY: Duration = Duration::from_secs(30);
+
+struct Entry {
+    // The last successfully resolved address.
+    addr: Option<model::Endpoint>,
+    // `None` if due immediately.
+    resolved_at: Option<Instant>,
+    failed: bool,
+}
+
+// Keeps the addresses of hostname endpoints.
+pub struct Resolver {
+    lookup: Lookup,
+    hosts: HashMap<Host, Entry>,
+}
+
+impl Resolver {
+    #[inline]
+    pub fn new() -> Self {
+        Self::with_lookup(Box::new(|host, port| {
+            Ok((host, port).to_socket_addrs()?.collect())
+        }))
+    }
+
+    #[inline]
+    pub fn with_lookup(lookup: Lookup) -> Self {
+        Self {
+            lookup,
+            hosts: HashMap::new(),
+        }
+    }
+
+    // Returns `None` for hostnames that have not been resolved yet.
+    pub fn endpoint(&self, e: &model::HostEndpoint) -> Option<model::Endpoint> {
+        match e {
+            model::HostEndpoint::Addr(v) => Some(*v),
+            model::HostEndpoint::Name(host, port) => self.hosts.get(&(host.clone(), *port))?.addr,
+        }
+    }
+
+    // Makes all hosts due for a lookup.
+    pub fn refresh_all(&mut self) {
+        for ent in self.hosts.values_mut() {
+            ent.resolved_at = None;
+        }
+    }
+
+    // Resolves the hosts in `wanted` that are due. Hosts in `stale` are due
+    // after `stale_after` instead of `interval`. Returns when the next host is
+    // due.
+    pub fn update(
+        &mut self,
+        wanted: HashSet<Host>,
+        stale: &HashSet<Host>,
+        interval: Duration,
+        stale_after: Duration,
+        now: Instant,
+    ) -> Option<Instant> {
+        self.hosts.retain(|h, _| wanted.contains(h));
+
+        let mut next: Option<Instant> = None;
+        for host in wanted {
+            let is_stale = stale.contains(&host);
+            let due = |ent: &Entry| match ent.resolved_at {
+                None => now,
+                Some(t) if ent.failed => t + RETRY,
+                Some(t) if is_stale => t + interval.min(stale_after),
+                Some(t) => t + interval,
+            };
+            let t = match self.hosts.get(&host) {
+                Some(ent) if due(ent) > now => due(ent),
+                _ => {
+                    self.resolve(host.clone(), now);
+                    due(&self.hosts[&host])
+                }
+            };
+            next = Some(next.map_or(t, |v| v.min(t)));
+        }
+        next
+    }
+
+    fn resolve(&mut self, host: Host, now: Instant) {
+        let r = (self.lookup)(&host.0, host.1).and_then(|addrs| {
+            addrs
+                .into_iter()
+                .next()
+                .map(model::Endpoint::from)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses"))
+        });
+
+        let ent = self.hosts.entry(host.clone()).or_insert(Entry {
+            addr: None,
+            resolved_at: None,
+            failed: false,
+        });
+        ent.resolved_at = Some(now);
+        match r {
+            Ok(addr) => {
+                ent.failed = false;
+                if ent.addr != Some(addr) {
+                    log!(
+                        5,
+                        "host_resolved",
+                        { host: &host.0, port: u32::from(host.1), address: &addr },
+                        "Resolved {}:{} to {}",
+                        &host.0,
+                        host.1,
+                        addr
+                    );
+                    ent.addr = Some(addr);
+                }
+            }
+            Err(e) => {
+                ent.failed = true;
+                log!(
+                    4,
+                    "resolve_failed",
+                    { host: &host.0, port: u32::from(host.1), error: e.to_string() },
+                    "Failed to resolve {}:{}: {}",
+                    &host.0,
+                    host.1,
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Resolver;
+    use crate::model::HostEndpoint;
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_update() {
+        let addr: Rc<RefCell<Option<SocketAddr>>> = Rc::default();
+        let lookups = Rc::new(RefCell::new(0));
+        let mut r = {
+            let (addr, lookups) = (addr.clone(), lookups.clone());
+            Resolver::with_lookup(Box::new(move |host, port| {
+                assert_eq!((host, port), ("vpn.example.org", 656));
+                *lookups.borrow_mut() += 1;
+                match *addr.borrow() {
+                    Some(v) => Ok(vec![v]),
+                    None => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+                }
+            }))
+        };
+
+        let e = HostEndpoint::from_str("vpn.example.org:656").unwrap();
+        assert_eq!(e.to_string(), "vpn.example.org:656");
+        let host = ("vpn.example.org".to_owned(), 656);
+        let wanted = || [host.clone()].iter().cloned().collect::<HashSet<_>>();
+        let fresh = HashSet::new();
+        let stale = wanted();
+        let secs = Duration::from_secs;
+        let t0 = Instant::now();
+
+        // Failures are retried sooner.
+        assert_eq!(
+            r.update(wanted(), &fresh, secs(300), secs(180), t0),
+            Some(t0 + secs(30))
+        );
+        assert_eq!(r.endpoint(&e), None);
+
+        *addr.borrow_mut() = Some("192.0.2.2:656".parse().unwrap());
+        let t1 = t0 + secs(30);
+        assert_eq!(
+            r.update(wanted(), &fresh, secs(300), secs(180), t1),
+            Some(t1 + secs(300))
+        );
+        assert_eq!(r.endpoint(&e).unwrap().to_string(), "192.0.2.2:656");
+
+        // Not due yet.
+        *addr.borrow_mut() = Some("192.0.2.3:656".parse().unwrap());
+        r.update(wanted(), &fresh, secs(300), secs(180), t1 + secs(100));
+        assert_eq!(*lookups.borrow(), 2);
+
+        // Stale hosts are looked up early. Failures keep the old address.
+        let t2 = t1 + secs(180);
+        *addr.borrow_mut() = None;
+        r.update(wanted(), &stale, secs(300), secs(180), t2);
+        assert_eq!(*lookups.borrow(), 3);
+        assert_eq!(r.endpoint(&e).unwrap().to_string(), "192.0.2.2:656");
+
+        *addr.borrow_mut() = Some("192.0.2.3:656".parse().unwrap());
+        r.refresh_all();
+        r.update(wanted(), &fresh, secs(300), secs(180), t2);
+        assert_eq!(r.endpoint(&e).unwrap().to_string(), "192.0.2.3:656");
+
+        // Hosts that are no longer used are forgotten.
+        assert_eq!(
+            r.update(HashSet::new(), &fresh, secs(300), secs(180), t2),
+            None
+        );
+        assert_eq!(r.endpoint(&e), None);
+
+        let e = HostEndpoint::from_str("192.0.2.4:656").unwrap();
+        assert_eq!(r.endpoint(&e).unwrap().to_string(), "192.0.2.4:656");
+        assert!(HostEndpoint::from_str("vpn.example.org").is_err());
+        assert!(HostEndpoint::from_str("-vpn.example.org:656").is_err());
+    }
+}
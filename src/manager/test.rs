@@ -0,0 +1,1272 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use super::{failover, resolver, Manager, Source};
+use crate::testutil::TempDir;
+use crate::wg::mock::{self, Change};
+use crate::{config, model, proto};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, io};
+
+fn key(i: u8) -> model::Key {
+    model::Key::from_bytes([i; 32])
+}
+
+fn source_config(name: &str) -> config::Source {
+    config::Source {
+        name: name.to_owned(),
+        urls: vec![format!("file:///nonexistent/{}.json", name)],
+        shuffle_mirrors: false,
+        psk: None,
+        ipv4: model::Ipv4Set::from(model::Ipv4Net::from_str("10.0.0.0/8").unwrap()),
+        ipv6: model::Ipv6Set::new(),
+        required: false,
+        allow_road_warriors: true,
+        verify_key: None,
+        signature_url: None,
+        monotonic_serial: false,
+        on_error: None,
+        priority: 0,
+        include_tags: vec![],
+        exclude_tags: vec![],
+    }
+}
+
+fn source_data(json: &str) -> proto::Source {
+    serde_json::from_str(json).unwrap()
+}
+
+fn server(k: u8, endpoint: &str, ipv4: &str) -> String {
+    format!(
+        r#"{{"public_key": "{}", "endpoint": "{}", "ipv4": ["{}"]}}"#,
+        key(k),
+        endpoint,
+        ipv4
+    )
+}
+
+fn road_warrior(k: u8, base: u8, ipv4: &str) -> String {
+    format!(
+        r#"{{"public_key": "{}", "base": "{}", "ipv4": ["{}"]}}"#,
+        key(k),
+        key(base),
+        ipv4
+    )
+}
+
+struct Env {
+    dir: TempDir,
+    dev: mock::Device,
+    m: Manager,
+}
+
+impl Env {
+    fn new(local: u8) -> Self {
+        let dir = TempDir::new();
+        let dev = mock::Device::new(key(local));
+        let c = config::Config {
+            runtime_directory: Some(dir.0.clone()),
+            ..Default::default()
+        };
+        let m = Manager::with_device(Box::new(dev.clone()), c).unwrap();
+        Self { dir, dev, m }
+    }
+
+    // Adds a source that has already been fetched and is not due for a refresh.
+    fn add_source(&mut self, name: &str, data: proto::Source) {
+        self.m.sources.push(Source {
+            data,
+            next_update: Instant::now() + Duration::from_secs(1 << 20),
+            ..Source::new(source_config(name))
+        });
+    }
+
+    fn set_source(&mut self, i: usize, data: proto::Source) {
+        self.m.sources[i].data = data;
+    }
+
+    fn update(&mut self) -> Vec<Change> {
+        self.m.update().unwrap();
+        self.dev.take_changes()
+    }
+
+    fn update_at(&mut self, t: SystemTime) -> Vec<Change> {
+        self.m.update_at(t).unwrap();
+        self.dev.take_changes()
+    }
+}
+
+fn changed_keys(changes: &[Change]) -> Vec<(bool, model::Key)> {
+    changes
+        .iter()
+        .map(|c| match c {
+            Change::Set(k, _) => (true, *k),
+            Change::Remove(k) => (false, *k),
+        })
+        .collect()
+}
+
+#[test]
+fn test_initial_apply() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}, {}, {}], "road_warriors": [{}, {}]}}"#,
+            server(1, "192.0.2.1:656", "10.0.1.0/24"),
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(3, "192.0.2.3:656", "10.0.3.0/24"),
+            road_warrior(4, 1, "10.0.4.1/32"),
+            road_warrior(5, 2, "10.0.5.1/32"),
+        )),
+    );
+
+    let changes = e.update();
+    assert_eq!(
+        changed_keys(&changes),
+        vec![(true, key(2)), (true, key(3)), (true, key(4))]
+    );
+
+    let peers = e.dev.peers();
+    assert_eq!(peers, e.m.current);
+    assert!(!peers.peers.contains_key(&key(1)));
+
+    let p2 = &peers.peers[&key(2)];
+    assert_eq!(p2.endpoint.unwrap().to_string(), "192.0.2.2:656");
+    assert_eq!(
+        p2.ipv4.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+        vec!["10.0.2.0/24", "10.0.5.1/32"]
+    );
+
+    let p4 = &peers.peers[&key(4)];
+    assert!(p4.endpoint.is_none());
+
+    assert!(e.update().is_empty());
+}
+
+#[test]
+fn test_source_change() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}, {}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(3, "192.0.2.3:656", "10.0.3.0/24"),
+        )),
+    );
+    assert_eq!(e.update().len(), 2);
+
+    e.set_source(
+        0,
+        source_data(&format!(
+            r#"{{"servers": [{}, {}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(3, "192.0.2.33:656", "10.0.3.0/24"),
+        )),
+    );
+    let changes = e.update();
+    assert_eq!(changed_keys(&changes), vec![(true, key(3))]);
+    match changes[0] {
+        Change::Set(_, ref p) => {
+            assert_eq!(p.endpoint.unwrap().to_string(), "192.0.2.33:656");
+        }
+        _ => unreachable!(),
+    }
+
+    e.set_source(
+        0,
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(3, "192.0.2.33:656", "10.0.3.0/24"),
+        )),
+    );
+    assert_eq!(changed_keys(&e.update()), vec![(false, key(2))]);
+    assert_eq!(e.dev.peers().peers.len(), 1);
+}
+
+#[test]
+fn test_disallowed_ips() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(2, "192.0.2.2:656", "192.168.0.0/24"),
+        )),
+    );
+    e.update();
+
+    let (_, errs, _, _, _) = e.m.make_config(key(1), SystemTime::now());
+    assert_eq!(errs.len(), 1);
+    assert!(errs[0].important());
+    assert!(e.dev.peers().peers[&key(2)].ipv4.is_empty());
+}
+
+#[test]
+fn test_overlapping_ips() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}, {}], "road_warriors": [{}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(3, "192.0.2.3:656", "10.0.2.128/25"),
+            road_warrior(4, 2, "10.0.2.5/32"),
+        )),
+    );
+    e.add_source(
+        "b",
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(5, "192.0.2.5:656", "10.0.2.128/26"),
+        )),
+    );
+
+    // Overlaps are not reported unless asked for.
+    let (_, errs, _, _, _) = e.m.make_config(key(1), SystemTime::now());
+    assert!(errs.is_empty());
+
+    e.m.global_config.on_overlap = config::OverlapPolicy::Warn;
+    let (cfg, errs, _, _, _) = e.m.make_config(key(1), SystemTime::now());
+    let keys: Vec<_> = errs.iter().map(|err| err.peer).collect();
+    assert_eq!(keys, vec![key(3), key(5)]);
+    assert!(errs.iter().all(|err| !err.important()));
+    assert_eq!(cfg.peers[&key(2)].ipv4.len(), 2);
+    assert_eq!(cfg.peers[&key(5)].ipv4.len(), 1);
+
+    e.m.global_config.on_overlap = config::OverlapPolicy::KeepFirst;
+    let (cfg, errs, _, _, _) = e.m.make_config(key(1), SystemTime::now());
+    assert_eq!(errs.len(), 2);
+    assert!(errs.iter().all(|err| err.important()));
+    assert!(cfg.peers[&key(3)].ipv4.is_empty());
+    assert!(cfg.peers[&key(5)].ipv4.is_empty());
+}
+
+#[test]
+fn test_merge_policy() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "central",
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+        )),
+    );
+    e.add_source(
+        "local",
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(2, "192.0.2.22:656", "10.0.22.0/24"),
+        )),
+    );
+    e.m.sources[1].config.priority = 10;
+    let make = |e: &Env| {
+        let (cfg, errs, _, _, _) = e.m.make_config(key(1), SystemTime::now());
+        let p = &cfg.peers[&key(2)];
+        (p.endpoint.unwrap().to_string(), p.ipv4.len(), errs.len())
+    };
+
+    assert_eq!(make(&e), ("192.0.2.22:656".to_owned(), 2, 1));
+    e.m.global_config.merge = config::MergePolicy::PriorityWins;
+    assert_eq!(make(&e), ("192.0.2.22:656".to_owned(), 1, 1));
+    let (_, errs, _, _, _) = e.m.make_config(key(1), SystemTime::now());
+    assert_eq!(errs[0].src, "central");
+    assert_eq!(errs[0].message(), "overridden by higher-priority source");
+    assert!(!errs[0].important());
+    e.m.global_config.merge = config::MergePolicy::Union;
+    assert_eq!(make(&e), ("192.0.2.22:656".to_owned(), 2, 0));
+
+    // Sources with the same priority are taken in order.
+    e.m.sources[1].config.priority = 0;
+    e.m.global_config.merge = config::MergePolicy::PriorityWins;
+    assert_eq!(make(&e), ("192.0.2.2:656".to_owned(), 2, 1));
+}
+
+#[test]
+fn test_error_policy() {
+    let good = |endpoint: &str| server(2, endpoint, "10.0.2.0/24");
+    let bad = server(3, "192.0.2.3:656", "192.168.0.0/24");
+    let data =
+        |servers: &[&str]| source_data(&format!(r#"{{"servers": [{}]}}"#, servers.join(",")));
+
+    let mut e = Env::new(1);
+    e.add_source("a", data(&[&good("192.0.2.2:656"), &bad]));
+    e.add_source("b", data(&[]));
+    e.update();
+    assert_eq!(e.dev.peers().peers.len(), 2);
+    assert_eq!(e.m.errors[0].action(), config::ErrorPolicy::Apply);
+
+    e.m.global_config.on_error = config::ErrorPolicy::DropPeers;
+    assert_eq!(changed_keys(&e.update()), vec![(false, key(3))]);
+    assert_eq!(e.m.errors[0].action(), config::ErrorPolicy::DropPeers);
+
+    // The source falls back to what was last applied from it.
+    e.m.sources[0].config.on_error = Some(config::ErrorPolicy::RejectSource);
+    e.set_source(0, data(&[&good("192.0.2.2:656")]));
+    assert!(e.update().is_empty());
+    e.set_source(0, data(&[&good("192.0.2.22:656"), &bad]));
+    assert!(e.update().is_empty());
+    assert_eq!(e.m.errors.len(), 1);
+    assert_eq!(e.m.errors[0].action(), config::ErrorPolicy::RejectSource);
+    assert_eq!(
+        e.dev.peers().peers[&key(2)].endpoint.unwrap().to_string(),
+        "192.0.2.2:656"
+    );
+
+    // Nothing changes, not even the peers of other sources.
+    e.m.sources[0].config.on_error = Some(config::ErrorPolicy::RejectUpdate);
+    e.set_source(1, data(&[&server(4, "192.0.2.4:656", "10.0.4.0/24")]));
+    assert!(e.update().is_empty());
+    assert_eq!(e.m.errors[0].action(), config::ErrorPolicy::RejectUpdate);
+
+    e.set_source(0, data(&[&good("192.0.2.22:656")]));
+    assert_eq!(
+        changed_keys(&e.update()),
+        vec![(true, key(2)), (true, key(4))]
+    );
+    assert!(e.m.errors.is_empty());
+}
+
+#[test]
+fn test_reject_source_unchanged() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24")
+        )),
+    );
+    e.add_source(
+        "b",
+        source_data(&format!(
+            r#"{{"servers": [{}], "road_warriors": [{}]}}"#,
+            server(3, "192.0.2.3:656", "10.0.3.0/24"),
+            road_warrior(5, 2, "10.0.5.1/32")
+        )),
+    );
+    e.m.sources[1].config.on_error = Some(config::ErrorPolicy::RejectSource);
+    e.update();
+    assert_eq!(e.dev.peers().peers.len(), 2);
+
+    // The base disappears from another source. There is nothing different to
+    // fall back to, so only the road warrior is left out.
+    e.set_source(0, source_data("{}"));
+    assert_eq!(changed_keys(&e.update()), vec![(false, key(2))]);
+    assert!(e.dev.peers().peers.contains_key(&key(3)));
+    assert_eq!(e.m.errors.len(), 1);
+    assert_eq!(e.m.errors[0].message(), "unknown base peer");
+    assert_eq!(e.m.errors[0].action(), config::ErrorPolicy::DropPeers);
+}
+
+#[test]
+fn test_scheduled_next() {
+    let t = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{
+                "servers": [{}],
+                "next": {{
+                    "update_at": "2033-05-18T03:33:20Z",
+                    "servers": [{}, {}],
+                    "road_warriors": [{}]
+                }}
+            }}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(2, "192.0.2.2:656", "10.0.2.0/25"),
+            server(3, "192.0.2.3:656", "10.0.2.128/25"),
+            road_warrior(4, 3, "10.0.4.1/32"),
+        )),
+    );
+
+    let before = Instant::now();
+    let wakeup = e.m.update_at(t - Duration::from_secs(30)).unwrap();
+    assert!(wakeup <= Instant::now() + Duration::from_secs(30));
+    assert!(wakeup >= before + Duration::from_secs(29));
+    assert_eq!(changed_keys(&e.dev.take_changes()), vec![(true, key(2))]);
+
+    let changes = e.update_at(t);
+    assert_eq!(changed_keys(&changes), vec![(true, key(2)), (true, key(3))]);
+    let peers = e.dev.peers();
+    assert_eq!(peers.peers[&key(2)].ipv4[0].to_string(), "10.0.2.0/25");
+    assert_eq!(
+        peers.peers[&key(3)]
+            .ipv4
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>(),
+        vec!["10.0.2.128/25", "10.0.4.1/32"]
+    );
+
+    assert!(e.update_at(t + Duration::from_secs(30)).is_empty());
+}
+
+#[test]
+fn test_scheduled_chain() {
+    let t = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{
+                "servers": [{}],
+                "road_warriors": [{}],
+                "next": [{{
+                    "update_at": "2033-05-18T03:33:20Z",
+                    "servers": [{}, {}],
+                    "road_warriors": [{}]
+                }}, {{
+                    "update_at": "2033-05-18T04:33:20Z",
+                    "servers": [{}],
+                    "road_warriors": [{}]
+                }}]
+            }}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            road_warrior(4, 2, "10.0.4.1/32"),
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(3, "192.0.2.3:656", "10.0.3.0/24"),
+            road_warrior(4, 2, "10.0.4.1/32"),
+            server(3, "192.0.2.3:656", "10.0.3.0/24"),
+            road_warrior(4, 3, "10.0.4.1/32"),
+        )),
+    );
+
+    let (_, _, _, _, t_cfg) = e.m.make_config(key(1), t - Duration::from_secs(30));
+    assert_eq!(t_cfg, t);
+    assert_eq!(
+        changed_keys(&e.update_at(t - Duration::from_secs(30))),
+        vec![(true, key(2))]
+    );
+
+    let (_, _, _, _, t_cfg) = e.m.make_config(key(1), t);
+    assert_eq!(t_cfg, t + Duration::from_secs(3600));
+    assert_eq!(changed_keys(&e.update_at(t)), vec![(true, key(3))]);
+    assert_eq!(e.m.status().scheduled.len(), 2);
+
+    let changes = e.update_at(t + Duration::from_secs(3600));
+    assert_eq!(
+        changed_keys(&changes),
+        vec![(true, key(3)), (false, key(2))]
+    );
+    assert_eq!(e.dev.peers().peers[&key(3)].ipv4.len(), 2);
+
+    let unordered = r#"{"next": [
+        {"update_at": "2033-05-18T04:33:20Z"},
+        {"update_at": "2033-05-18T03:33:20Z"}
+    ]}"#;
+    assert!(serde_json::from_str::<proto::Source>(unordered).is_err());
+
+    // Problems with a single configuration are reported as such.
+    let invalid = r#"{"next": {"update_at": 5}}"#;
+    let err = serde_json::from_str::<proto::Source>(invalid).unwrap_err();
+    assert!(err.to_string().contains("expected RFC3339 time"));
+
+    let cached = r#"{"servers": [], "next": null}"#;
+    assert_eq!(source_data(cached), proto::Source::empty());
+}
+
+#[test]
+fn test_validity_window() {
+    let t = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{
+                "servers": [{}],
+                "road_warriors": [{{
+                    "public_key": "{}",
+                    "base": "{}",
+                    "ipv4": ["10.0.4.1/32"],
+                    "not_before": "2033-05-18T03:33:20Z",
+                    "not_after": "2033-05-18T04:33:20Z"
+                }}]
+            }}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            key(4),
+            key(2),
+        )),
+    );
+
+    let before = Instant::now();
+    let wakeup = e.m.update_at(t - Duration::from_secs(30)).unwrap();
+    assert!(wakeup <= Instant::now() + Duration::from_secs(30));
+    assert!(wakeup >= before + Duration::from_secs(29));
+    assert_eq!(e.dev.peers().peers[&key(2)].ipv4.len(), 1);
+    e.dev.take_changes();
+
+    let (_, _, _, _, t_cfg) = e.m.make_config(key(1), t);
+    assert_eq!(t_cfg, t + Duration::from_secs(3600));
+    assert_eq!(changed_keys(&e.update_at(t)), vec![(true, key(2))]);
+    assert_eq!(e.dev.peers().peers[&key(2)].ipv4.len(), 2);
+
+    let changes = e.update_at(t + Duration::from_secs(3600));
+    assert_eq!(changed_keys(&changes), vec![(true, key(2))]);
+    assert_eq!(e.dev.peers().peers[&key(2)].ipv4.len(), 1);
+}
+
+#[test]
+fn test_state_persisted() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+        )),
+    );
+    e.update();
+
+    let c = config::Config {
+        runtime_directory: Some(e.dir.0.clone()),
+        ..Default::default()
+    };
+    let m = Manager::with_device(Box::new(e.dev.clone()), c).unwrap();
+    assert_eq!(m.current, e.dev.peers());
+}
+
+#[test]
+fn test_reconcile() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}, {}, {}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(3, "192.0.2.3:656", "10.0.3.0/24"),
+            server(4, "192.0.2.4:656", "10.0.4.0/24"),
+        )),
+    );
+    e.update();
+
+    let mut p2 = e.dev.peers().peers[&key(2)].clone();
+    p2.ipv4.clear();
+    e.dev.set_peer(key(2), Some(p2));
+    e.dev.set_peer(key(3), None);
+    let mut p4 = e.dev.peers().peers[&key(4)].clone();
+    p4.endpoint = Some(model::Endpoint::from_str("198.51.100.4:1234").unwrap());
+    e.dev.set_peer(key(4), Some(p4));
+    let p9 = e.dev.peers().peers[&key(2)].clone();
+    e.dev.set_peer(key(9), Some(p9.clone()));
+
+    // Not due yet.
+    assert!(e.update().is_empty());
+
+    e.m.next_reconcile = Instant::now();
+    let changes = e.update();
+    assert_eq!(changed_keys(&changes), vec![(true, key(2)), (true, key(3))]);
+
+    let peers = e.dev.peers().peers;
+    assert_eq!(peers[&key(2)], e.m.current.peers[&key(2)]);
+    assert_eq!(peers[&key(3)], e.m.current.peers[&key(3)]);
+    assert_eq!(
+        peers[&key(4)].endpoint.unwrap().to_string(),
+        "198.51.100.4:1234"
+    );
+    assert_eq!(peers[&key(9)], p9);
+}
+
+#[test]
+fn test_reconcile_shared_ips() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}, {}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(3, "192.0.2.3:656", "10.0.2.0/24"),
+        )),
+    );
+    e.update();
+    let peers = e.dev.peers().peers;
+    assert_eq!(peers[&key(2)].ipv4.len() + peers[&key(3)].ipv4.len(), 1);
+
+    // The interface can only give the prefix to one of the peers, which is not
+    // a change to undo.
+    e.m.next_reconcile = Instant::now();
+    assert!(e.update().is_empty());
+    assert_eq!(e.dev.peers().peers, peers);
+}
+
+const SIGNED_KEY: &str = "RWQBAgMEBQYHCAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4";
+const SIGNED_DATA: &str = r#"{"servers": [{"public_key": "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=", "endpoint": "192.0.2.2:656", "ipv4": ["10.0.2.0/24"]}]}"#;
+const SIGNED_SIG: &str = "untrusted comment: signature from minisign secret key
+RUQBAgMEBQYHCFtrleR4Z5oTxyxbaKJvjRgtFggD6NMlcqmqCvx01WQcKPSPs8/fo9iFFdotv9zB37R2vMKdJzHglzgDgWgUTgI=
+trusted comment: timestamp:0
+YXHM9AVeO5jM/rEJeruO76L4eibLi8u8caZcT4NuJAl8pkr9fP62bFLkYq1C17RchxecMB7atKIlQsCRtzSkCA==
+";
+
+#[test]
+fn test_signed_source() {
+    let e = Env::new(1);
+    let path = |name: &str| {
+        let mut p = e.dir.0.clone();
+        p.push(name);
+        p
+    };
+    let fetch = |name: &str| {
+        let mut config = source_config("a");
+        config.urls = vec![format!("file://{}", path(name).display())];
+        config.verify_key = Some(SIGNED_KEY.parse().unwrap());
+        let mut src = Source::new(config);
+        let ok = e.m.updater.update(&mut src).0;
+        assert_eq!(ok, !src.data.config.servers.is_empty());
+        ok
+    };
+
+    fs::write(path("detached.json"), SIGNED_DATA).unwrap();
+    assert!(!fetch("detached.json"));
+    fs::write(path("detached.json.minisig"), SIGNED_SIG).unwrap();
+    assert!(fetch("detached.json"));
+
+    fs::write(path("tampered.json"), SIGNED_DATA.replace("/24", "/16")).unwrap();
+    fs::write(path("tampered.json.minisig"), SIGNED_SIG).unwrap();
+    assert!(!fetch("tampered.json"));
+
+    let embedded = serde_json::json!({
+        "signed": SIGNED_DATA,
+        "signature": SIGNED_SIG,
+    });
+    fs::write(path("embedded.json"), embedded.to_string()).unwrap();
+    assert!(fetch("embedded.json"));
+
+    let embedded = serde_json::json!({
+        "signed": SIGNED_DATA.replace("/24", "/16"),
+        "signature": SIGNED_SIG,
+    });
+    fs::write(path("embedded-bad.json"), embedded.to_string()).unwrap();
+    assert!(!fetch("embedded-bad.json"));
+}
+
+#[test]
+fn test_monotonic_serial() {
+    let dir = TempDir::new();
+    let mut path = dir.0.clone();
+    path.push("src.json");
+
+    let write = |serial: Option<u64>, endpoint: &str| {
+        let serial = match serial {
+            Some(v) => format!(r#""serial": {}, "#, v),
+            None => String::new(),
+        };
+        let data = format!(
+            r#"{{{}"servers": [{}]}}"#,
+            serial,
+            server(2, endpoint, "10.0.2.0/24")
+        );
+        fs::write(&path, data).unwrap();
+    };
+    let manager = || {
+        let mut src = source_config("a");
+        src.urls = vec![format!("file://{}", path.display())];
+        src.monotonic_serial = true;
+        let c = config::Config {
+            runtime_directory: Some(dir.0.clone()),
+            updater: config::UpdaterConfig {
+                cache_directory: Some(dir.0.clone()),
+                ..Default::default()
+            },
+            sources: vec![src],
+            ..Default::default()
+        };
+        Manager::with_device(Box::new(mock::Device::new(key(1))), c).unwrap()
+    };
+    let endpoint = |m: &Manager| m.sources[0].data.config.servers[0].endpoints[0].to_string();
+
+    write(Some(5), "192.0.2.2:656");
+    let mut m = manager();
+    assert_eq!(m.sources[0].data.serial, Some(5));
+
+    write(Some(4), "192.0.2.4:656");
+    assert!(!m.updater.update(&mut m.sources[0]).0);
+    write(Some(5), "192.0.2.5:656");
+    assert!(!m.updater.update(&mut m.sources[0]).0);
+    write(None, "192.0.2.5:656");
+    assert!(!m.updater.update(&mut m.sources[0]).0);
+    assert_eq!(endpoint(&m), "192.0.2.2:656");
+
+    write(Some(5), "192.0.2.2:656");
+    assert!(m.updater.update(&mut m.sources[0]).0);
+
+    // The cached serial survives a restart.
+    write(Some(4), "192.0.2.4:656");
+    let mut m = manager();
+    assert_eq!(m.sources[0].data.serial, Some(5));
+    assert_eq!(endpoint(&m), "192.0.2.2:656");
+
+    write(Some(6), "192.0.2.6:656");
+    assert!(m.updater.update(&mut m.sources[0]).0);
+    assert_eq!(endpoint(&m), "192.0.2.6:656");
+}
+
+#[test]
+fn test_conditional_fetch() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let body = format!(
+        r#"{{"servers": [{}]}}"#,
+        server(2, "192.0.2.2:656", "10.0.2.0/24")
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    let srv = thread::spawn(move || {
+        for _ in 0..3 {
+            let (conn, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut conditional = false;
+            loop {
+                let mut line = String::new();
+                r.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if line.eq_ignore_ascii_case("if-none-match: \"v1\"\r\n") {
+                    conditional = true;
+                }
+            }
+            tx.send(conditional).unwrap();
+
+            let mut conn = conn;
+            if conditional {
+                write!(
+                    conn,
+                    "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            } else {
+                write!(
+                    conn,
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        }
+    });
+
+    let dir = TempDir::new();
+    let updater = super::updater::Updater::new(config::UpdaterConfig {
+        cache_directory: Some(dir.0.clone()),
+        ..Default::default()
+    });
+    let source = || {
+        let mut config = source_config("a");
+        config.urls = vec![format!("http://127.0.0.1:{}/peers.json", port)];
+        Source::new(config)
+    };
+
+    let mut src = source();
+    assert!(updater.update(&mut src).0);
+    assert!(!rx.recv().unwrap());
+    assert_eq!(src.data.config.servers.len(), 1);
+    let data = src.data.clone();
+
+    assert!(updater.update(&mut src).0);
+    assert!(rx.recv().unwrap());
+    assert_eq!(src.data, data);
+    assert!(src.next_update > Instant::now());
+
+    // The validators are cached together with the source.
+    let mut src = source();
+    assert!(updater.cache_load(&mut src));
+    assert!(updater.update(&mut src).0);
+    assert!(rx.recv().unwrap());
+    assert_eq!(src.data, data);
+
+    srv.join().unwrap();
+}
+
+#[test]
+fn test_mirrors() {
+    let dir = TempDir::new();
+    let url = |name: &str| format!("file://{}/{}", dir.0.display(), name);
+    let updater = super::updater::Updater::new(Default::default());
+
+    let mut config = source_config("a");
+    config.urls = vec![url("down.json"), url("stale.json"), url("up.json")];
+    config.monotonic_serial = true;
+    let mut src = Source::new(config);
+    src.data.serial = Some(2);
+
+    let data = |serial: u64| {
+        format!(
+            r#"{{"serial": {}, "servers": [{}]}}"#,
+            serial,
+            server(2, "192.0.2.2:656", "10.0.2.0/24")
+        )
+    };
+    fs::write(dir.0.join("stale.json"), data(1)).unwrap();
+
+    assert!(!updater.update(&mut src).0);
+    assert_eq!(src.backoff, Some(Duration::from_secs(10)));
+    assert!(src.next_update <= Instant::now() + Duration::from_secs(10));
+    assert!(src.last_error.is_some());
+    assert_eq!(src.data.serial, Some(2));
+
+    assert!(!updater.update(&mut src).0);
+    let b = Duration::from_secs(10);
+    assert_eq!(src.backoff, Some(b + b / 3));
+
+    fs::write(dir.0.join("up.json"), data(3)).unwrap();
+    assert!(updater.update(&mut src).0);
+    assert!(src.backoff.is_none());
+    assert!(src.last_error.is_none());
+    assert!(src.last_update.is_some());
+    assert_eq!(src.data.serial, Some(3));
+}
+
+#[test]
+fn test_local_files() {
+    let mut e = Env::new(1);
+    e.add_source("a", proto::Source::empty());
+    e.add_source("b", proto::Source::empty());
+    e.m.sources[0].config.urls = vec![
+        "file:///etc/wgconfd/a.json".to_owned(),
+        "https://example.org/a.json".to_owned(),
+    ];
+    e.m.sources[0].config.verify_key = Some(SIGNED_KEY.parse().unwrap());
+    e.m.sources[1].config.urls = vec!["file:///etc/wgconfd/a.json".to_owned()];
+
+    assert_eq!(
+        e.m.local_files(),
+        vec![
+            PathBuf::from("/etc/wgconfd/a.json"),
+            PathBuf::from("/etc/wgconfd/a.json.minisig"),
+        ]
+    );
+
+    let now = Instant::now();
+    e.m.file_changed(Path::new("/etc/wgconfd/a.json.minisig"));
+    assert!(e.m.sources[0].next_update <= Instant::now());
+    assert!(e.m.sources[1].next_update > now);
+
+    e.m.refresh_all();
+    assert!(e.m.sources[1].next_update <= Instant::now());
+}
+
+#[test]
+fn test_reload() {
+    let mut e = Env::new(1);
+    let data = |k: u8| {
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(k, "192.0.2.2:656", &format!("10.0.{}.0/24", k)),
+        ))
+    };
+    e.add_source("a", data(2));
+    e.add_source("b", data(3));
+    e.update();
+
+    let config = |sources: Vec<config::Source>| config::Config {
+        global: config::GlobalConfig {
+            max_keepalive: 25,
+            ..Default::default()
+        },
+        sources,
+        ..Default::default()
+    };
+
+    let mut required = source_config("c");
+    required.required = true;
+    let c = config(vec![source_config("a"), required]);
+    assert!(e.m.reload(c).is_err());
+    assert_eq!(e.m.sources.len(), 2);
+    assert_eq!(e.m.global_config.max_keepalive, 0);
+
+    let c = config(vec![source_config("c"), source_config("a")]);
+    e.m.reload(c).unwrap();
+    let names: Vec<&str> = e.m.sources.iter().map(|s| &s.config.name[..]).collect();
+    assert_eq!(names, vec!["c", "a"]);
+    assert_eq!(e.m.sources[1].data, data(2));
+
+    let changes = e.update();
+    assert_eq!(
+        changed_keys(&changes),
+        vec![(true, key(2)), (false, key(3))]
+    );
+    assert_eq!(e.dev.peers().peers[&key(2)].keepalive, 25);
+}
+
+#[test]
+fn test_status() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{
+                "servers": [{}],
+                "next": {{"update_at": "2100-01-01T00:00:00Z", "servers": []}}
+            }}"#,
+            server(2, "192.0.2.2:656", "192.168.0.0/24"),
+        )),
+    );
+    e.m.sources[0].from_cache = true;
+    e.update();
+
+    let status = serde_json::to_value(e.m.status()).unwrap();
+    let src = &status["sources"][0];
+    assert_eq!(src["name"], "a");
+    assert_eq!(src["from_cache"], true);
+    assert!(src["last_update"].is_null());
+    assert_eq!(status["scheduled"][0]["source"], "a");
+    assert_eq!(
+        status["scheduled"][0]["update_at"],
+        "2100-01-01T00:00:00.000000000Z"
+    );
+    assert_eq!(status["errors"].as_array().unwrap().len(), 1);
+    assert!(status["config"]["peers"][key(2).to_string()].is_object());
+}
+
+#[test]
+fn test_status_psk() {
+    let mut e = Env::new(1);
+    let psk = key(9);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+        )),
+    );
+    e.m.sources[0].config.psk = Some(model::Secret::from_key(psk));
+    e.update();
+    assert!(e.m.current.peers[&key(2)].psk.is_some());
+
+    let reply = crate::control_request(&e.m, "status");
+    let status: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+    assert_eq!(
+        status["config"]["peers"][key(2).to_string()]["has_psk"],
+        true
+    );
+    let reply = String::from_utf8(reply).unwrap();
+    assert!(!reply.contains(&psk.to_string()));
+}
+
+#[test]
+fn test_host_endpoints() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut e = Env::new(1);
+    let addr: Rc<RefCell<Option<&str>>> = Rc::default();
+    let lookups = Rc::new(RefCell::new(0));
+    e.m.resolver = {
+        let (addr, lookups) = (addr.clone(), lookups.clone());
+        resolver::Resolver::with_lookup(Box::new(move |host, port| {
+            assert_eq!(host, "vpn.example.org");
+            *lookups.borrow_mut() += 1;
+            match *addr.borrow() {
+                Some(v) => Ok(vec![format!("{}:{}", v, port).parse().unwrap()]),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            }
+        }))
+    };
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{}, {}]}}"#,
+            server(2, "vpn.example.org:656", "10.0.2.0/24"),
+            server(3, "192.0.2.3:656", "10.0.3.0/24"),
+        )),
+    );
+
+    // Peers whose hostname cannot be resolved are configured without an
+    // endpoint.
+    let endpoint = |e: &Env| e.dev.peers().peers[&key(2)].endpoint;
+    assert_eq!(e.update().len(), 2);
+    assert!(endpoint(&e).is_none());
+
+    *addr.borrow_mut() = Some("192.0.2.2");
+    e.m.resolver.refresh_all();
+    assert_eq!(changed_keys(&e.update()), vec![(true, key(2))]);
+    assert_eq!(endpoint(&e).unwrap().to_string(), "192.0.2.2:656");
+
+    // Not due yet.
+    *addr.borrow_mut() = Some("192.0.2.22");
+    assert!(e.update().is_empty());
+
+    e.m.resolver.refresh_all();
+    assert_eq!(changed_keys(&e.update()), vec![(true, key(2))]);
+    assert_eq!(endpoint(&e).unwrap().to_string(), "192.0.2.22:656");
+
+    // The same address again does not touch the interface.
+    e.m.resolver.refresh_all();
+    assert!(e.update().is_empty());
+
+    // The lookup is repeated once the handshake becomes stale.
+    let sysnow = SystemTime::now();
+    e.dev
+        .set_handshake(key(2), sysnow - Duration::from_secs(170));
+    let t = e.m.update_at(sysnow).unwrap();
+    assert!(t <= Instant::now() + Duration::from_secs(10));
+
+    e.m.global_config.stale_handshake_sec = 1;
+    e.dev.set_handshake(key(2), SystemTime::now());
+    *addr.borrow_mut() = Some("192.0.2.23");
+    let n = *lookups.borrow();
+    assert!(e.update().is_empty());
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(changed_keys(&e.update()), vec![(true, key(2))]);
+    assert_eq!(*lookups.borrow(), n + 1);
+    assert_eq!(endpoint(&e).unwrap().to_string(), "192.0.2.23:656");
+
+    // An override in `[peer.*]` takes precedence.
+    e.m.global_config.peers.insert(
+        key(2),
+        config::Peer {
+            source: None,
+            endpoint: Some(model::HostEndpoint::from_str("192.0.2.200:656").unwrap()),
+            psk: None,
+            keepalive: None,
+        },
+    );
+    e.update();
+    assert_eq!(endpoint(&e).unwrap().to_string(), "192.0.2.200:656");
+}
+
+#[test]
+fn test_endpoint_failover() {
+    let data = || {
+        source_data(&format!(
+            r#"{{"servers": [{{"public_key": "{}", "endpoint": ["192.0.2.2:656", "[2001:db8::2]:656"], "keepalive": 25, "ipv4": ["10.0.2.0/24"]}}]}}"#,
+            key(2),
+        ))
+    };
+    let endpoint = |changes: &[Change]| match changes {
+        [Change::Set(_, p)] => p.endpoint.unwrap().to_string(),
+        _ => panic!("unexpected changes: {:?}", changes),
+    };
+
+    let mut e = Env::new(1);
+    e.add_source("a", data());
+    let t = SystemTime::now();
+    assert_eq!(endpoint(&e.update_at(t)), "192.0.2.2:656");
+    assert!(e.update_at(t + Duration::from_secs(170)).is_empty());
+
+    // No handshake, so the next candidate is tried.
+    let t = t + Duration::from_secs(180);
+    assert_eq!(endpoint(&e.update_at(t)), "[2001:db8::2]:656");
+
+    e.dev.set_handshake(key(2), t + Duration::from_secs(5));
+    assert!(e.update_at(t + Duration::from_secs(10)).is_empty());
+    assert!(e.update_at(t + Duration::from_secs(180)).is_empty());
+
+    // The working candidate is remembered across restarts.
+    let c = config::Config {
+        runtime_directory: Some(e.dir.0.clone()),
+        ..Default::default()
+    };
+    let mut m = Manager::with_device(Box::new(e.dev.clone()), c).unwrap();
+    m.sources.push(Source {
+        data: data(),
+        ..Source::new(source_config("a"))
+    });
+    let (config, _, _, _, _) = m.make_config(key(1), SystemTime::now());
+    assert_eq!(
+        config.peers[&key(2)].endpoint.unwrap().to_string(),
+        "[2001:db8::2]:656"
+    );
+
+    let invalid = format!(
+        r#"{{"servers": [{{"public_key": "{}", "endpoint": "192.0.2.2"}}]}}"#,
+        key(2)
+    );
+    let err = serde_json::from_str::<proto::Source>(&invalid).unwrap_err();
+    assert!(err.to_string().starts_with("invalid address"));
+}
+
+#[test]
+fn test_idle_server_kept() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{"servers": [{{"public_key": "{}", "endpoint": ["192.0.2.2:656", "[2001:db8::2]:656"], "ipv4": ["10.0.2.0/24"]}}]}}"#,
+            key(2),
+        )),
+    );
+    let t = SystemTime::now();
+    assert_eq!(e.update_at(t).len(), 1);
+
+    // Without a keepalive, no handshake is expected.
+    for i in 1..5 {
+        assert!(e.update_at(t + Duration::from_secs(i * 180)).is_empty());
+    }
+    assert_eq!(
+        e.dev.peers().peers[&key(2)].endpoint.unwrap().to_string(),
+        "192.0.2.2:656"
+    );
+}
+
+#[test]
+fn test_road_warrior_bases() {
+    let data = || {
+        source_data(&format!(
+            r#"{{"servers": [{}, {}], "road_warriors": [{{"public_key": "{}", "base": ["{}", "{}"], "ipv4": ["10.0.5.1/32"]}}]}}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            server(3, "192.0.2.3:656", "10.0.3.0/24"),
+            key(5),
+            key(2),
+            key(3),
+        ))
+    };
+    let routed = |e: &Env| {
+        let peers = e.dev.peers().peers;
+        [key(2), key(3)]
+            .iter()
+            .find(|k| peers[k].ipv4.iter().any(|v| v.to_string() == "10.0.5.1/32"))
+            .copied()
+    };
+
+    // Without handshakes, the first base is used.
+    let mut e = Env::new(1);
+    e.add_source("a", data());
+    e.update();
+    assert_eq!(routed(&e), Some(key(2)));
+
+    let t = SystemTime::now();
+    e.dev.set_handshake(key(3), t);
+    let changes = e.update_at(t);
+    assert_eq!(changed_keys(&changes), vec![(true, key(2)), (true, key(3))]);
+    assert_eq!(routed(&e), Some(key(3)));
+
+    // The first base is checked again without waiting for any other event,
+    // and used again as soon as it has a handshake.
+    let next = e.m.update_at(t).unwrap();
+    assert!(next <= Instant::now() + failover::RECHECK);
+    let t = t + failover::RECHECK;
+    e.dev.set_handshake(key(2), t);
+    e.update_at(t);
+    assert_eq!(routed(&e), Some(key(2)));
+
+    // Once all handshakes are stale, the first base is used again.
+    e.update_at(t + Duration::from_secs(180));
+    assert_eq!(routed(&e), Some(key(2)));
+
+    // Any base configures the road warrior itself.
+    let mut e = Env::new(3);
+    e.add_source("a", data());
+    e.update();
+    assert_eq!(e.dev.peers().peers[&key(5)].ipv4.len(), 1);
+    assert!(e.dev.peers().peers[&key(2)].ipv4.len() == 1);
+    assert!(e.m.errors.is_empty());
+
+    let invalid = format!(
+        r#"{{"road_warriors": [{{"public_key": "{}", "base": "AAAA"}}]}}"#,
+        key(5)
+    );
+    let err = serde_json::from_str::<proto::Source>(&invalid).unwrap_err();
+    let want = base64::DecodeError::InvalidLength.to_string();
+    assert!(err.to_string().starts_with(&want));
+}
+
+#[test]
+fn test_peer_metadata() {
+    let mut e = Env::new(1);
+    e.add_source(
+        "a",
+        source_data(&format!(
+            r#"{{
+                "servers": [{}],
+                "road_warriors": [{{
+                    "public_key": "{}",
+                    "base": "{}",
+                    "ipv4": ["192.168.5.1/32"],
+                    "name": "laptop-alice",
+                    "description": "Alice's laptop",
+                    "tags": ["staff"]
+                }}]
+            }}"#,
+            server(2, "192.0.2.2:656", "10.0.2.0/24"),
+            key(5),
+            key(2),
+        )),
+    );
+    e.update();
+
+    assert_eq!(
+        e.m.errors[0].to_string(),
+        "invalid peer [a]/laptop-alice: all IPs removed"
+    );
+
+    let status = serde_json::to_value(e.m.status()).unwrap();
+    assert_eq!(status["errors"][0]["peer_name"], "laptop-alice");
+    assert_eq!(status["errors"][0]["source"], "a");
+    assert!(status["peer_info"].as_object().unwrap().is_empty());
+
+    e.set_source(
+        0,
+        source_data(&format!(
+            r#"{{"servers": [{{
+                "public_key": "{}",
+                "endpoint": "192.0.2.2:656",
+                "ipv4": ["10.0.2.0/24"],
+                "name": "gw-sofia",
+                "tags": ["prod", "eu"]
+            }}]}}"#,
+            key(2),
+        )),
+    );
+    e.update();
+    let status = serde_json::to_value(e.m.status()).unwrap();
+    let info = &status["peer_info"][key(2).to_string()];
+    assert_eq!(info["source"], "a");
+    assert_eq!(info["name"], "gw-sofia");
+    assert!(info["description"].is_null());
+    assert_eq!(info["tags"], serde_json::json!(["prod", "eu"]));
+}
+
+#[test]
+fn test_tag_filters() {
+    let tagged = |k: u8, tags: &str| {
+        format!(
+            r#"{{"public_key": "{}", "endpoint": "192.0.2.{}:656", "ipv4": ["10.0.{}.0/24"], "tags": [{}]}}"#,
+            key(k),
+            k,
+            k,
+            tags
+        )
+    };
+    let data = source_data(&format!(
+        r#"{{"servers": [{}, {}, {}, {}, {}]}}"#,
+        tagged(1, ""),
+        tagged(2, r#""prod""#),
+        tagged(3, r#""staging""#),
+        tagged(4, r#""prod", "eu""#),
+        tagged(5, ""),
+    ));
+
+    let mut e = Env::new(1);
+    e.add_source("a", data.clone());
+    e.m.sources[0].config.include_tags = vec!["prod".to_owned()];
+    let changes = e.update();
+    assert_eq!(changed_keys(&changes), vec![(true, key(2)), (true, key(4))]);
+    // The local peer is not reported.
+    let skipped: Vec<_> = e.m.skipped.iter().map(|s| s.peer).collect();
+    assert_eq!(skipped, vec![key(3), key(5)]);
+    assert!(e.m.errors.is_empty());
+
+    e.m.sources[0].config.exclude_tags = vec!["eu".to_owned()];
+    let changes = e.update();
+    assert_eq!(changed_keys(&changes), vec![(false, key(4))]);
+
+    e.m.sources[0].config.include_tags.clear();
+    let changes = e.update();
+    assert_eq!(changed_keys(&changes), vec![(true, key(3)), (true, key(5))]);
+    assert_eq!(e.m.skipped.len(), 1);
+    assert_eq!(e.m.skipped[0].to_string(), format!("[a]/[{}]", key(4)));
+}
@@ -6,8 +6,34 @@ use super::Source;
 use crate::{config, fileutil, proto};
 use std::ffi::{OsStr, OsString};
 use std::path::PathBuf;
-use std::time::{Duration, Instant};
-use std::{fs, io};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, io, str};
+
+// HTTP cache validators of the last fetched version of a source.
+#[derive(
+    serde_derive::Serialize, serde_derive::Deserialize, Default, Clone, PartialEq, Eq, Debug,
+)]
+pub(super) struct Validators {
+    // The mirror the validators came from.
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+enum Fetched {
+    NotModified,
+    Data(Vec<u8>, Validators),
+}
 
 pub(super) struct Updater {
     config: config::UpdaterConfig,
@@ -18,14 +44,16 @@ impl Updater {
         Self { config }
     }
 
-    fn cache_path(&self, s: &Source) -> Option<PathBuf> {
+    fn cache_path(&self, s: &Source, suffix: &str) -> Option<PathBuf> {
         let mut p = self.config.cache_directory.as_ref()?.clone();
-        p.push(&s.config.name);
+        let mut name = OsString::from(&s.config.name);
+        name.push(suffix);
+        p.push(name);
         Some(p)
     }
 
     fn cache_update(&self, src: &Source) {
-        let path = match self.cache_path(src) {
+        let path = match self.cache_path(src, "") {
             Some(v) => v,
             None => return,
         };
@@ -34,13 +62,81 @@ impl Updater {
         match fileutil::update(&path, &data) {
             Ok(()) => {}
             Err(e) => {
-                eprintln!("<4>Failed to cache [{}]: {}", &src.config.name, e);
+                log!(
+                    4,
+                    "cache_failed",
+                    { source: &src.config.name, error: e.to_string() },
+                    "Failed to cache [{}]: {}",
+                    &src.config.name,
+                    e
+                );
+                return;
+            }
+        }
+
+        let path = self.cache_path(src, ".http").unwrap();
+        let r = if src.validators.is_empty() {
+            match fs::remove_file(&path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                r => r,
+            }
+        } else {
+            let data = serde_json::to_vec(&src.validators).unwrap();
+            fileutil::update(&path, &data)
+        };
+        if let Err(e) = r {
+            log!(
+                4,
+                "cache_failed",
+                { source: &src.config.name, error: e.to_string() },
+                "Failed to cache HTTP validators of [{}]: {}",
+                &src.config.name,
+                e
+            );
+        }
+    }
+
+    fn cache_load_validators(&self, src: &mut Source) {
+        src.validators = Validators::default();
+        let path = match self.cache_path(src, ".http") {
+            Some(v) => v,
+            None => return,
+        };
+
+        let data = match fileutil::load(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    log!(
+                        4,
+                        "cache_load_failed",
+                        { source: &src.config.name, error: e.to_string() },
+                        "Failed to read HTTP validators of [{}]: {}",
+                        &src.config.name,
+                        e
+                    );
+                }
+                return;
+            }
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(v) => src.validators = v,
+            Err(e) => {
+                log!(
+                    4,
+                    "cache_load_failed",
+                    { source: &src.config.name, error: e.to_string() },
+                    "Failed to load HTTP validators of [{}]: {}",
+                    &src.config.name,
+                    e
+                );
             }
         }
     }
 
     pub fn cache_load(&self, src: &mut Source) -> bool {
-        let path = match self.cache_path(src) {
+        let path = match self.cache_path(src, "") {
             Some(v) => v,
             None => return false,
         };
@@ -49,7 +145,14 @@ impl Updater {
             Ok(data) => data,
             Err(e) => {
                 if e.kind() != io::ErrorKind::NotFound {
-                    eprintln!("<3>Failed to read [{}] from cache: {}", &src.config.name, e);
+                    log!(
+                        3,
+                        "cache_load_failed",
+                        { source: &src.config.name, error: e.to_string() },
+                        "Failed to read [{}] from cache: {}",
+                        &src.config.name,
+                        e
+                    );
                 }
                 return false;
             }
@@ -59,39 +162,116 @@ impl Updater {
         src.data = match serde::Deserialize::deserialize(&mut de) {
             Ok(r) => r,
             Err(e) => {
-                eprintln!("<3>Failed to load [{}] from cache: {}", &src.config.name, e);
+                log!(
+                    3,
+                    "cache_load_failed",
+                    { source: &src.config.name, error: e.to_string() },
+                    "Failed to load [{}] from cache: {}",
+                    &src.config.name,
+                    e
+                );
                 return false;
             }
         };
 
+        self.cache_load_validators(src);
+        src.from_cache = true;
         true
     }
 
     pub fn update(&self, src: &mut Source) -> (bool, Instant) {
         let refresh = self.refresh_time();
 
-        let r = fetch_source(&src.config.url);
-        let now = Instant::now();
-        let r = match r {
-            Ok(r) => {
-                eprintln!("<6>Updated [{}]", &src.config.url);
-                src.data = r;
-                src.backoff = None;
-                src.next_update = now + refresh;
-                self.cache_update(src);
-                return (true, now);
+        let mut urls: Vec<&str> = src.config.urls.iter().map(String::as_str).collect();
+        if src.config.shuffle_mirrors {
+            use rand::seq::SliceRandom;
+            urls.shuffle(&mut rand::thread_rng());
+        }
+
+        let mut err = None;
+        for (i, url) in urls.iter().enumerate() {
+            let r = fetch_source(&src.config, url, &src.validators).and_then(|r| {
+                if let Some((ref r, _)) = r {
+                    if src.config.monotonic_serial {
+                        check_serial(&src.data, r)?;
+                    }
+                }
+                Ok(r)
+            });
+            let now = Instant::now();
+            match r {
+                Ok(None) => {
+                    log!(
+                        6,
+                        "source_not_modified",
+                        { source: &src.config.name, url: url },
+                        "Not modified [{}]",
+                        url
+                    );
+                    src.fetch_successes += 1;
+                    src.last_update = Some(SystemTime::now());
+                    src.last_error = None;
+                    src.backoff = None;
+                    src.next_update = now + refresh;
+                    return (true, now);
+                }
+                Ok(Some((r, validators))) => {
+                    log!(
+                        6,
+                        "source_updated",
+                        { source: &src.config.name, url: url },
+                        "Updated [{}]",
+                        url
+                    );
+                    src.data = r;
+                    src.validators = validators;
+                    src.from_cache = false;
+                    src.fetch_successes += 1;
+                    src.last_update = Some(SystemTime::now());
+                    src.last_error = None;
+                    src.backoff = None;
+                    src.next_update = now + refresh;
+                    self.cache_update(src);
+                    return (true, now);
+                }
+                Err(r) => {
+                    if i + 1 < urls.len() {
+                        log!(
+                            4,
+                            "mirror_failed",
+                            { source: &src.config.name, url: url, error: r.to_string() },
+                            "Failed to fetch [{}], trying next mirror: {}",
+                            url,
+                            &r
+                        );
+                    }
+                    err = Some(r);
+                }
             }
-            Err(r) => r,
-        };
+        }
 
-        let b = src
-            .backoff
-            .unwrap_or_else(|| Duration::from_secs(10).min(refresh / 10));
+        let now = Instant::now();
+        let r = err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no URL"));
+        let b = match src.backoff {
+            Some(b) => (b + b / 3).min(refresh / 3),
+            None => Duration::from_secs(10).min(refresh / 10),
+        };
         src.next_update = now + b;
-        src.backoff = Some((b + b / 3).min(refresh / 3));
-        eprintln!(
-            "<3>Failed to update [{}], retrying after {:.1?}: {}",
-            &src.config.url, b, &r
+        src.backoff = Some(b);
+        src.last_error = Some(r.to_string());
+        src.fetch_failures += 1;
+        log!(
+            3,
+            "source_failed",
+            {
+                source: &src.config.name,
+                error: r.to_string(),
+                retry_sec: b.as_secs_f64(),
+            },
+            "Failed to update [{}], retrying after {:.1?}: {}",
+            &src.config.name,
+            b,
+            &r
         );
         (false, now)
     }
@@ -101,7 +281,10 @@ impl Updater {
     }
 }
 
-fn fetch_source(url: &str) -> io::Result<proto::Source> {
+// Only HTTP sources are fetched conditionally. If `validators` is given and the
+// server says that the source has not changed, `Fetched::NotModified` is
+// returned.
+fn fetch(url: &str, validators: Option<&Validators>) -> io::Result<Fetched> {
     use std::env;
     use std::process::{Command, Stdio};
 
@@ -124,6 +307,23 @@ fn fetch_source(url: &str) -> io::Result<proto::Source> {
     proc.arg("10");
     proc.arg("--max-filesize");
     proc.arg("1M");
+
+    let http = url.starts_with("http://") || url.starts_with("https://");
+    if http {
+        proc.arg("--dump-header");
+        proc.arg("-");
+        if let Some(v) = validators {
+            if let Some(ref etag) = v.etag {
+                proc.arg("--header");
+                proc.arg(format!("If-None-Match: {}", etag));
+            }
+            if let Some(ref last_modified) = v.last_modified {
+                proc.arg("--header");
+                proc.arg(format!("If-Modified-Since: {}", last_modified));
+            }
+        }
+    }
+
     proc.arg("--");
     proc.arg(url);
 
@@ -132,21 +332,151 @@ fn fetch_source(url: &str) -> io::Result<proto::Source> {
     if !out.status.success() {
         let msg = String::from_utf8_lossy(&out.stderr);
         let msg = msg.replace('\n', "; ");
-        return Err(io::Error::new(io::ErrorKind::Other, msg));
+        return Err(io::Error::other(msg));
     }
 
-    let mut de = serde_json::Deserializer::from_slice(&out.stdout);
+    if !http {
+        return Ok(Fetched::Data(out.stdout, Validators::default()));
+    }
+
+    let (status, mut v, body) = split_response(&out.stdout)?;
+    if !v.is_empty() {
+        v.url = Some(url.to_owned());
+    }
+    if status == 304 {
+        if validators.is_none() {
+            return Err(io::Error::other("unexpected 304 response"));
+        }
+        return Ok(Fetched::NotModified);
+    }
+    Ok(Fetched::Data(body.to_vec(), v))
+}
+
+// Splits the output of `curl --dump-header -` into the status code and the
+// validators of the final response, and the body.
+fn split_response(mut out: &[u8]) -> io::Result<(u32, Validators, &[u8])> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+
+    let mut status = None;
+    let mut v = Validators::default();
+    while out.starts_with(b"HTTP/") {
+        let end = out
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(invalid)?;
+        let head = str::from_utf8(&out[..end]).map_err(|_| invalid())?;
+        out = &out[end + 4..];
+
+        let mut lines = head.split("\r\n");
+        status = lines
+            .next()
+            .and_then(|l| l.split(' ').nth(1))
+            .and_then(|s| s.parse().ok());
+        v = Validators::default();
+        for line in lines {
+            let i = match line.find(':') {
+                Some(i) => i,
+                None => continue,
+            };
+            let (name, value) = (line[..i].trim(), line[i + 1..].trim());
+            if name.eq_ignore_ascii_case("etag") {
+                v.etag = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("last-modified") {
+                v.last_modified = Some(value.to_owned());
+            }
+        }
+    }
+
+    let status = status.ok_or_else(invalid)?;
+    Ok((status, v, out))
+}
+
+// Splits a signed source into the source itself and the signature.
+fn unwrap_signed(data: Vec<u8>) -> (Vec<u8>, Option<String>) {
+    match serde_json::from_slice::<proto::Signed>(&data) {
+        Ok(v) => (v.signed.into_bytes(), Some(v.signature)),
+        Err(_) => (data, None),
+    }
+}
+
+fn fetch_source(
+    c: &config::Source,
+    url: &str,
+    validators: &Validators,
+) -> io::Result<Option<(proto::Source, Validators)>> {
+    let validators = if validators.url.as_deref() == Some(url) {
+        Some(validators)
+    } else {
+        None
+    };
+    let (data, validators) = match fetch(url, validators)? {
+        Fetched::NotModified => return Ok(None),
+        Fetched::Data(data, v) => (data, v),
+    };
+    let (data, signature) = unwrap_signed(data);
+
+    if let Some(ref key) = c.verify_key {
+        let signature = match signature {
+            Some(v) => v,
+            None => {
+                let url = match c.signature_url {
+                    Some(ref v) => v.clone(),
+                    None => format!("{}.minisig", url),
+                };
+                let data = match fetch(&url, None)? {
+                    Fetched::Data(data, _) => data,
+                    Fetched::NotModified => unreachable!(),
+                };
+                String::from_utf8(data).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid signature encoding")
+                })?
+            }
+        };
+        key.verify(&data, &signature)?;
+    }
+
+    let mut de = serde_json::Deserializer::from_slice(&data);
     let r = serde::Deserialize::deserialize(&mut de)?;
-    Ok(r)
+    Ok(Some((r, validators)))
+}
+
+fn check_serial(old: &proto::Source, new: &proto::Source) -> io::Result<()> {
+    let new_serial = match new.serial {
+        Some(v) => v,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "source has no serial",
+            ))
+        }
+    };
+    let old_serial = match old.serial {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    if new_serial < old_serial {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("serial {} is older than {}", new_serial, old_serial),
+        ));
+    }
+    if new_serial == old_serial && new != old {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("serial {} reused for different content", new_serial),
+        ));
+    }
+    Ok(())
 }
 
 pub fn load_source(path: &OsStr) -> io::Result<proto::Source> {
     let mut data = Vec::new();
     {
         use std::io::Read;
-        let mut f = fs::File::open(&path)?;
+        let mut f = fs::File::open(path)?;
         f.read_to_end(&mut data)?;
     }
+    let (data, _) = unwrap_signed(data);
 
     let mut de = serde_json::Deserializer::from_slice(&data);
     let r = serde::Deserialize::deserialize(&mut de)?;
@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use crate::{fileutil, proto};
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn timestamp(t: SystemTime) -> f64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs_f64()
+}
+
+struct Writer<'a> {
+    out: String,
+    interface: &'a str,
+}
+
+impl Writer<'_> {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn value(&mut self, name: &str, labels: &[(&str, &str)], v: f64) {
+        write!(
+            self.out,
+            "{}{{interface=\"{}\"",
+            name,
+            escape(self.interface)
+        )
+        .unwrap();
+        for (k, l) in labels {
+            write!(self.out, ",{}=\"{}\"", k, escape(l)).unwrap();
+        }
+        writeln!(self.out, "}} {}", v).unwrap();
+    }
+}
+
+// Formats the status in the Prometheus text exposition format.
+pub fn render(interface: &str, s: &proto::Status<'_>) -> String {
+    let mut w = Writer {
+        out: String::new(),
+        interface,
+    };
+
+    w.header(
+        "wgconfd_source_fetch_successes_total",
+        "counter",
+        "Successful fetches of the source.",
+    );
+    for src in &s.sources {
+        w.value(
+            "wgconfd_source_fetch_successes_total",
+            &[("source", src.name)],
+            src.fetch_successes as f64,
+        );
+    }
+
+    w.header(
+        "wgconfd_source_fetch_failures_total",
+        "counter",
+        "Failed fetches of the source.",
+    );
+    for src in &s.sources {
+        w.value(
+            "wgconfd_source_fetch_failures_total",
+            &[("source", src.name)],
+            src.fetch_failures as f64,
+        );
+    }
+
+    w.header(
+        "wgconfd_source_backoff_seconds",
+        "gauge",
+        "Delay before retrying the source after a failure, 0 if it is up to date.",
+    );
+    for src in &s.sources {
+        w.value(
+            "wgconfd_source_backoff_seconds",
+            &[("source", src.name)],
+            src.backoff_sec.unwrap_or(0.0),
+        );
+    }
+
+    w.header(
+        "wgconfd_source_last_success_timestamp_seconds",
+        "gauge",
+        "Time of the last successful fetch of the source.",
+    );
+    for src in &s.sources {
+        if let Some(t) = src.last_update {
+            w.value(
+                "wgconfd_source_last_success_timestamp_seconds",
+                &[("source", src.name)],
+                timestamp(t),
+            );
+        }
+    }
+
+    w.header("wgconfd_peers", "gauge", "Number of managed peers.");
+    w.value("wgconfd_peers", &[], s.config.peers.len() as f64);
+
+    w.header(
+        "wgconfd_config_errors",
+        "gauge",
+        "Number of problems with the current configuration.",
+    );
+    for important in &[true, false] {
+        let n = s
+            .errors
+            .iter()
+            .filter(|e| e.important == *important)
+            .count();
+        w.value(
+            "wgconfd_config_errors",
+            &[("important", if *important { "true" } else { "false" })],
+            n as f64,
+        );
+    }
+
+    if let Some(t) = s.last_change {
+        w.header(
+            "wgconfd_last_change_timestamp_seconds",
+            "gauge",
+            "Time of the last change of the interface configuration.",
+        );
+        w.value("wgconfd_last_change_timestamp_seconds", &[], timestamp(t));
+    }
+
+    if let Some(t) = s.scheduled.iter().map(|u| u.update_at).min() {
+        w.header(
+            "wgconfd_next_config_timestamp_seconds",
+            "gauge",
+            "Time at which the next scheduled configuration takes effect.",
+        );
+        w.value("wgconfd_next_config_timestamp_seconds", &[], timestamp(t));
+    }
+
+    w.out
+}
+
+// The file is made world-readable so that the collector can read it.
+pub fn write(path: &Path, interface: &str, s: &proto::Status<'_>) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let mut tmp = fileutil::Writer::new_in(dir)?;
+    io::Write::write_all(tmp.file(), render(interface, s).as_bytes())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tmp.file()
+            .set_permissions(std::fs::Permissions::from_mode(0o644))?;
+    }
+    tmp.done().rename_to(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::render;
+    use crate::{model, proto};
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_render() {
+        let config = model::Config::empty();
+        let urls = vec!["https://example.org/a\"b.json".to_owned()];
+        let status = proto::Status {
+            sources: vec![proto::SourceStatus {
+                name: "a\"b",
+                urls: &urls,
+                serial: None,
+                from_cache: false,
+                last_update: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000)),
+                last_error: None,
+                fetch_successes: 3,
+                fetch_failures: 1,
+                backoff_sec: Some(13.5),
+                next_update: SystemTime::now(),
+            }],
+            config: (&config).into(),
+            peer_info: HashMap::new(),
+            scheduled: vec![proto::ScheduledUpdate {
+                source: "a",
+                update_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            }],
+            errors: vec![proto::ErrorStatus {
+                message: "error".to_owned(),
+                source: "a".to_owned(),
+                peer: model::Key::from_bytes([2; 32]),
+                peer_name: None,
+                important: true,
+                action: "apply",
+            }],
+            last_change: None,
+        };
+
+        let out = render("wg0", &status);
+        let lines: Vec<&str> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"wgconfd_source_fetch_successes_total{interface="wg0",source="a\"b"} 3"#,
+                r#"wgconfd_source_fetch_failures_total{interface="wg0",source="a\"b"} 1"#,
+                r#"wgconfd_source_backoff_seconds{interface="wg0",source="a\"b"} 13.5"#,
+                r#"wgconfd_source_last_success_timestamp_seconds{interface="wg0",source="a\"b"} 1500000000"#,
+                r#"wgconfd_peers{interface="wg0"} 0"#,
+                r#"wgconfd_config_errors{interface="wg0",important="true"} 1"#,
+                r#"wgconfd_config_errors{interface="wg0",important="false"} 0"#,
+                r#"wgconfd_next_config_timestamp_seconds{interface="wg0"} 1600000000"#,
+            ]
+        );
+    }
+}
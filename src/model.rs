@@ -3,8 +3,8 @@
 // Copyright 2019 Hristo Venev
 
 use crate::fileutil;
-use base64;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::str::FromStr;
 use std::{fmt, io};
@@ -18,6 +18,16 @@ pub type KeyParseError = base64::DecodeError;
 pub struct Key([u8; 32]);
 
 impl Key {
+    #[inline]
+    pub fn from_bytes(v: [u8; 32]) -> Self {
+        Self(v)
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
     pub fn from_base64(s: &[u8]) -> Result<Self, KeyParseError> {
         let mut v = Self([0; 32]);
         let l = base64::decode_config_slice(s, base64::STANDARD, &mut v.0)?;
@@ -109,6 +119,18 @@ impl Secret {
     }
 }
 
+impl Secret {
+    #[inline]
+    pub fn from_key(k: Key) -> Self {
+        Self(k)
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+}
+
 impl fmt::Display for Secret {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -123,6 +145,55 @@ impl fmt::Debug for Secret {
     }
 }
 
+// A minisign public key used to verify sources.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VerifyKey(minisign_verify::PublicKey);
+
+impl VerifyKey {
+    pub fn verify(&self, data: &[u8], signature: &str) -> io::Result<()> {
+        let signature = minisign_verify::Signature::decode(signature).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid signature: {}", e),
+            )
+        })?;
+        self.0.verify(data, &signature, false).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("signature verification failed: {}", e),
+            )
+        })
+    }
+}
+
+impl FromStr for VerifyKey {
+    type Err = minisign_verify::Error;
+    #[inline]
+    fn from_str(s: &str) -> Result<Self, minisign_verify::Error> {
+        minisign_verify::PublicKey::from_base64(s).map(Self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for VerifyKey {
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        struct VerifyKeyVisitor;
+        impl<'de> serde::de::Visitor<'de> for VerifyKeyVisitor {
+            type Value = VerifyKey;
+
+            #[inline]
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("minisign public key")
+            }
+
+            #[inline]
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+        de.deserialize_str(VerifyKeyVisitor)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Endpoint {
     address: Ipv6Addr,
@@ -163,19 +234,26 @@ impl fmt::Display for Endpoint {
     }
 }
 
+impl From<SocketAddr> for Endpoint {
+    #[inline]
+    fn from(v: SocketAddr) -> Self {
+        Self {
+            address: match v.ip() {
+                IpAddr::V4(a) => a.to_ipv6_mapped(),
+                IpAddr::V6(a) => a,
+            },
+            port: v.port(),
+        }
+    }
+}
+
 impl FromStr for Endpoint {
     type Err = NetParseError;
+    #[inline]
     fn from_str(s: &str) -> Result<Self, NetParseError> {
-        use std::net;
-        net::SocketAddr::from_str(s)
+        SocketAddr::from_str(s)
+            .map(Self::from)
             .map_err(|_| NetParseError::BadAddress)
-            .map(|v| Self {
-                address: match v.ip() {
-                    net::IpAddr::V4(a) => a.to_ipv6_mapped(),
-                    net::IpAddr::V6(a) => a,
-                },
-                port: v.port(),
-            })
     }
 }
 
@@ -222,6 +300,88 @@ impl<'de> serde::Deserialize<'de> for Endpoint {
     }
 }
 
+// An endpoint given either as an address or as a hostname that wgconfd
+// resolves.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum HostEndpoint {
+    Addr(Endpoint),
+    Name(String, u16),
+}
+
+impl HostEndpoint {
+    fn valid_name(s: &str) -> bool {
+        !s.is_empty()
+            && s.len() <= 253
+            && s.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && label
+                        .bytes()
+                        .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+            })
+    }
+}
+
+impl From<Endpoint> for HostEndpoint {
+    #[inline]
+    fn from(v: Endpoint) -> Self {
+        Self::Addr(v)
+    }
+}
+
+impl fmt::Display for HostEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Addr(v) => v.fmt(f),
+            Self::Name(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl FromStr for HostEndpoint {
+    type Err = NetParseError;
+    fn from_str(s: &str) -> Result<Self, NetParseError> {
+        if let Ok(v) = Endpoint::from_str(s) {
+            return Ok(Self::Addr(v));
+        }
+        let i = s.rfind(':').ok_or(NetParseError::BadAddress)?;
+        let (host, port) = (&s[..i], &s[i + 1..]);
+        let port = u16::from_str(port).map_err(|_| NetParseError::BadAddress)?;
+        if !Self::valid_name(host) {
+            return Err(NetParseError::BadAddress);
+        }
+        Ok(Self::Name(host.to_owned(), port))
+    }
+}
+
+impl serde::Serialize for HostEndpoint {
+    #[inline]
+    fn serialize<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for HostEndpoint {
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        struct HostEndpointVisitor;
+        impl<'de> serde::de::Visitor<'de> for HostEndpointVisitor {
+            type Value = HostEndpoint;
+
+            #[inline]
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("IP:port or host:port")
+            }
+
+            #[inline]
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+        de.deserialize_str(HostEndpointVisitor)
+    }
+}
+
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct Peer {
     pub endpoint: Option<Endpoint>,
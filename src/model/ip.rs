@@ -2,7 +2,7 @@
 //
 // Copyright 2019 Hristo Venev
 
-use serde;
+use std::collections::BTreeMap;
 use std::iter::{FromIterator, IntoIterator};
 pub use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
@@ -29,7 +29,7 @@ impl fmt::Display for NetParseError {
 }
 
 macro_rules! per_proto {
-    ($nett:ident ($addrt:ident; $expecting:expr); $intt:ident($bytes:expr); $sett:ident) => {
+    ($nett:ident ($addrt:ident; $expecting:expr); $intt:ident($bytes:expr); $sett:ident; $mapt:ident) => {
         #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
         pub struct $nett {
             address: $addrt,
@@ -46,7 +46,7 @@ macro_rules! per_proto {
                 }
                 if prefix_len != Self::BITS {
                     let val: $intt = address.into();
-                    if val & ($intt::max_value() >> prefix_len) != 0 {
+                    if val & ($intt::MAX >> prefix_len) != 0 {
                         return Err(NetParseError::NotNetworkAddress);
                     }
                 }
@@ -82,6 +82,39 @@ macro_rules! per_proto {
                 let v2: $intt = other.address.into();
                 v1 >> shift == v2 >> shift
             }
+
+            #[inline]
+            pub fn overlaps(&self, other: &Self) -> bool {
+                self.contains(other) || other.contains(self)
+            }
+
+            // The network with a shorter prefix that contains this one.
+            fn supernet(&self, prefix_len: u8) -> Self {
+                let v: $intt = self.address.into();
+                let v = if prefix_len == 0 {
+                    0
+                } else {
+                    v & ($intt::MAX << (Self::BITS - prefix_len))
+                };
+                Self {
+                    address: v.into(),
+                    prefix_len,
+                }
+            }
+
+            // The last single address in the network.
+            fn last(&self) -> Self {
+                let v: $intt = self.address.into();
+                let v = if self.prefix_len == Self::BITS {
+                    v
+                } else {
+                    v | ($intt::MAX >> self.prefix_len)
+                };
+                Self {
+                    address: v.into(),
+                    prefix_len: Self::BITS,
+                }
+            }
         }
 
         impl fmt::Display for $nett {
@@ -320,11 +353,50 @@ macro_rules! per_proto {
                 <Vec<$nett> as serde::Deserialize>::deserialize(de).map(Self::from)
             }
         }
+
+        // Networks with a value attached, which may overlap.
+        #[derive(Clone, Debug)]
+        pub struct $mapt<T> {
+            nets: BTreeMap<$nett, T>,
+        }
+
+        impl<T> Default for $mapt<T> {
+            #[inline]
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl<T> $mapt<T> {
+            #[inline]
+            pub fn new() -> Self {
+                Self {
+                    nets: BTreeMap::new(),
+                }
+            }
+
+            #[inline]
+            pub fn insert(&mut self, net: $nett, v: T) -> Option<T> {
+                self.nets.insert(net, v)
+            }
+
+            // Returns the entries that contain or are contained in `net`.
+            pub fn overlapping(&self, net: &$nett) -> Vec<(&$nett, &T)> {
+                let mut r = vec![];
+                for pfx in 0..net.prefix_len {
+                    if let Some(e) = self.nets.get_key_value(&net.supernet(pfx)) {
+                        r.push(e);
+                    }
+                }
+                r.extend(self.nets.range(*net..=net.last()));
+                r
+            }
+        }
     };
 }
 
-per_proto!(Ipv4Net(Ipv4Addr; "IPv4 network"); u32(4); Ipv4Set);
-per_proto!(Ipv6Net(Ipv6Addr; "IPv6 network"); u128(16); Ipv6Set);
+per_proto!(Ipv4Net(Ipv4Addr; "IPv4 network"); u32(4); Ipv4Set; Ipv4Map);
+per_proto!(Ipv6Net(Ipv6Addr; "IPv6 network"); u128(16); Ipv6Set; Ipv6Map);
 
 fn pfx_split(s: &str) -> Result<(&str, u8), NetParseError> {
     let i = match s.find('/') {
@@ -338,7 +410,7 @@ fn pfx_split(s: &str) -> Result<(&str, u8), NetParseError> {
 
 #[cfg(test)]
 mod test {
-    use super::{pfx_split, Ipv4Addr, Ipv4Net, Ipv4Set, Ipv6Addr, Ipv6Net};
+    use super::{pfx_split, Ipv4Addr, Ipv4Map, Ipv4Net, Ipv4Set, Ipv6Addr, Ipv6Map, Ipv6Net};
     use std::str::FromStr;
 
     #[test]
@@ -467,4 +539,37 @@ mod test {
             "0.0.0.0/0"
         );
     }
+
+    #[test]
+    fn test_map_overlapping() {
+        let net = |s| Ipv4Net::from_str(s).unwrap();
+        let mut m = Ipv4Map::new();
+        m.insert(net("10.0.0.0/8"), 1);
+        m.insert(net("10.1.0.0/16"), 2);
+        m.insert(net("10.1.2.0/24"), 3);
+        m.insert(net("10.2.0.0/16"), 4);
+        m.insert(net("192.0.2.0/24"), 5);
+
+        let found = |s| {
+            let mut v: Vec<i32> = m
+                .overlapping(&net(s))
+                .into_iter()
+                .map(|(_, v)| *v)
+                .collect();
+            v.sort();
+            v
+        };
+        assert_eq!(found("10.1.0.0/16"), vec![1, 2, 3]);
+        assert_eq!(found("10.1.2.3/32"), vec![1, 2, 3]);
+        assert_eq!(found("10.3.0.0/16"), vec![1]);
+        assert_eq!(found("0.0.0.0/0"), vec![1, 2, 3, 4, 5]);
+        assert_eq!(found("192.0.3.0/24"), Vec::<i32>::new());
+
+        let net6 = |s| Ipv6Net::from_str(s).unwrap();
+        let mut m6 = Ipv6Map::new();
+        m6.insert(net6("2001:db8::/32"), 1);
+        assert_eq!(m6.overlapping(&net6("2001:db8:1::/48")).len(), 1);
+        assert!(m6.overlapping(&net6("2001:db9::/32")).is_empty());
+        assert!(net6("::/0").overlaps(&net6("::1/128")));
+    }
 }
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use crate::proto;
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+// Talks to the service manager using the sd_notify protocol.
+pub struct Notifier {
+    sock: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    // Returns `None` if not started by a service manager that wants
+    // notifications. The variable is removed so that children do not see it.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = match env::var_os("NOTIFY_SOCKET") {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        env::remove_var("NOTIFY_SOCKET");
+        if path.is_empty() {
+            return Ok(None);
+        }
+
+        let addr = socket_addr(&path)?;
+        let sock = UnixDatagram::unbound()?;
+        Ok(Some(Self { sock, addr }))
+    }
+
+    pub fn send(&self, msg: &str) -> io::Result<()> {
+        self.sock.send_to_addr(msg.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn socket_addr(path: &OsStr) -> io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+
+    match path.as_bytes().split_first() {
+        Some((b'@', name)) => SocketAddr::from_abstract_name(name),
+        _ => SocketAddr::from_pathname(path),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn socket_addr(path: &OsStr) -> io::Result<SocketAddr> {
+    SocketAddr::from_pathname(path)
+}
+
+// Returns how often the service manager expects to be pinged, if at all.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok();
+    let pid = env::var("WATCHDOG_PID").ok();
+    env::remove_var("WATCHDOG_USEC");
+    env::remove_var("WATCHDOG_PID");
+
+    if let Some(pid) = pid {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+// A one-line summary of the health of the sources, for `STATUS=`.
+pub fn describe(s: &proto::Status<'_>) -> String {
+    let failing = s.sources.iter().filter(|v| v.last_error.is_some()).count();
+    let mut r = format!(
+        "{} sources ({} failing), {} peers",
+        s.sources.len(),
+        failing,
+        s.config.peers.len()
+    );
+    if !s.errors.is_empty() {
+        r.push_str(&format!(", {} configuration problems", s.errors.len()));
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::{socket_addr, Notifier};
+    use crate::testutil::TempDir;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_send() {
+        let dir = TempDir::new();
+        let path = dir.0.join("notify");
+
+        let server = UnixDatagram::bind(&path).unwrap();
+        let n = Notifier {
+            sock: UnixDatagram::unbound().unwrap(),
+            addr: socket_addr(path.as_os_str()).unwrap(),
+        };
+        n.send("READY=1\nSTATUS=ok\n").unwrap();
+
+        let mut buf = [0_u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=ok\n");
+    }
+}
@@ -2,8 +2,9 @@
 //
 // Copyright 2019 Hristo Venev
 
-use crate::model::{Endpoint, Ipv4Net, Ipv6Net, Key};
-use serde_derive;
+use crate::model::{self, HostEndpoint, Ipv4Net, Ipv6Net, Key};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::time::SystemTime;
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -12,13 +13,38 @@ pub struct Peer {
     pub ipv4: Vec<Ipv4Net>,
     pub ipv6: Vec<Ipv6Net>,
     pub keepalive: u32,
+    // The peer is only configured between these times.
+    pub not_before: Option<SystemTime>,
+    pub not_after: Option<SystemTime>,
+    // Only used to describe the peer to humans.
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl Peer {
+    #[inline]
+    pub fn valid_at(&self, t: SystemTime) -> bool {
+        self.not_before.map_or(true, |v| t >= v) && self.not_after.map_or(true, |v| t < v)
+    }
+
+    // The first time after `t` at which the peer becomes valid or invalid.
+    #[inline]
+    pub fn next_change(&self, t: SystemTime) -> Option<SystemTime> {
+        self.not_before
+            .into_iter()
+            .chain(self.not_after)
+            .filter(|v| *v > t)
+            .min()
+    }
 }
 
-#[serde(from = "ServerRepr", into = "ServerRepr")]
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "ServerRepr", into = "ServerRepr")]
 pub struct Server {
     pub peer: Peer,
-    pub endpoint: Endpoint,
+    // Candidates to fail over between, in order of preference. Never empty.
+    pub endpoints: Vec<HostEndpoint>,
 }
 
 #[derive(serde_derive::Serialize, serde_derive::Deserialize)]
@@ -31,61 +57,109 @@ struct ServerRepr {
     ipv6: Vec<Ipv6Net>,
     #[serde(default)]
     keepalive: u32,
-    endpoint: Endpoint,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serde_utc::serialize_opt",
+        deserialize_with = "serde_utc::deserialize_opt"
+    )]
+    not_before: Option<SystemTime>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serde_utc::serialize_opt",
+        deserialize_with = "serde_utc::deserialize_opt"
+    )]
+    not_after: Option<SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(rename = "endpoint", with = "one_or_many")]
+    endpoints: Vec<HostEndpoint>,
 }
 
 impl From<Server> for ServerRepr {
     #[inline]
     fn from(v: Server) -> Self {
-        let Server { peer, endpoint } = v;
+        let Server { peer, endpoints } = v;
         let Peer {
             public_key,
             ipv4,
             ipv6,
             keepalive,
+            not_before,
+            not_after,
+            name,
+            description,
+            tags,
         } = peer;
         Self {
             public_key,
             ipv4,
             ipv6,
-            endpoint,
+            endpoints,
             keepalive,
+            not_before,
+            not_after,
+            name,
+            description,
+            tags,
         }
     }
 }
 
-impl From<ServerRepr> for Server {
+impl TryFrom<ServerRepr> for Server {
+    type Error = &'static str;
+
     #[inline]
-    fn from(v: ServerRepr) -> Self {
+    fn try_from(v: ServerRepr) -> Result<Self, &'static str> {
         let ServerRepr {
             public_key,
             ipv4,
             ipv6,
             keepalive,
-            endpoint,
+            not_before,
+            not_after,
+            name,
+            description,
+            tags,
+            endpoints,
         } = v;
-        Self {
+        if endpoints.is_empty() {
+            return Err("at least one endpoint required");
+        }
+        Ok(Self {
             peer: Peer {
                 public_key,
                 ipv4,
                 ipv6,
                 keepalive,
+                not_before,
+                not_after,
+                name,
+                description,
+                tags,
             },
-            endpoint,
-        }
+            endpoints,
+        })
     }
 }
 
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Clone, PartialEq, Eq, Debug)]
-#[serde(from = "RoadWarriorRepr", into = "RoadWarriorRepr")]
+#[serde(try_from = "RoadWarriorRepr", into = "RoadWarriorRepr")]
 pub struct RoadWarrior {
     pub peer: Peer,
-    pub base: Key,
+    // The servers the road warrior talks to, in order of preference. Never
+    // empty.
+    pub bases: Vec<Key>,
 }
 
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(deny_unknown_fields)]
-pub struct RoadWarriorRepr {
+struct RoadWarriorRepr {
     public_key: Key,
     #[serde(default)]
     ipv4: Vec<Ipv4Net>,
@@ -93,48 +167,94 @@ pub struct RoadWarriorRepr {
     ipv6: Vec<Ipv6Net>,
     #[serde(default)]
     keepalive: u32,
-    base: Key,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serde_utc::serialize_opt",
+        deserialize_with = "serde_utc::deserialize_opt"
+    )]
+    not_before: Option<SystemTime>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serde_utc::serialize_opt",
+        deserialize_with = "serde_utc::deserialize_opt"
+    )]
+    not_after: Option<SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(rename = "base", with = "one_or_many")]
+    bases: Vec<Key>,
 }
 
 impl From<RoadWarrior> for RoadWarriorRepr {
     #[inline]
     fn from(v: RoadWarrior) -> Self {
-        let RoadWarrior { peer, base } = v;
+        let RoadWarrior { peer, bases } = v;
         let Peer {
             public_key,
             ipv4,
             ipv6,
             keepalive,
+            not_before,
+            not_after,
+            name,
+            description,
+            tags,
         } = peer;
         Self {
             public_key,
             ipv4,
             ipv6,
             keepalive,
-            base,
+            not_before,
+            not_after,
+            name,
+            description,
+            tags,
+            bases,
         }
     }
 }
 
-impl From<RoadWarriorRepr> for RoadWarrior {
+impl TryFrom<RoadWarriorRepr> for RoadWarrior {
+    type Error = &'static str;
+
     #[inline]
-    fn from(v: RoadWarriorRepr) -> Self {
+    fn try_from(v: RoadWarriorRepr) -> Result<Self, &'static str> {
         let RoadWarriorRepr {
             public_key,
             ipv4,
             ipv6,
             keepalive,
-            base,
+            not_before,
+            not_after,
+            name,
+            description,
+            tags,
+            bases,
         } = v;
-        Self {
+        if bases.is_empty() {
+            return Err("at least one base required");
+        }
+        Ok(Self {
             peer: Peer {
                 public_key,
                 ipv4,
                 ipv6,
                 keepalive,
+                not_before,
+                not_after,
+                name,
+                description,
+                tags,
             },
-            base,
-        }
+            bases,
+        })
     }
 }
 
@@ -145,21 +265,36 @@ pub struct SourceConfig {
 }
 
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Clone, PartialEq, Eq, Debug)]
-#[serde(from = "SourceRepr", into = "SourceRepr")]
+#[serde(try_from = "SourceRepr", into = "SourceRepr")]
 pub struct Source {
+    pub serial: Option<u64>,
     pub config: SourceConfig,
-    pub next: Option<(SystemTime, SourceConfig)>,
+    // Configurations that take effect at the given times, in ascending order.
+    pub next: Vec<(SystemTime, SourceConfig)>,
 }
 
 impl Source {
     pub fn empty() -> Self {
         Self {
+            serial: None,
             config: SourceConfig {
                 servers: vec![],
                 road_warriors: vec![],
             },
-            next: None,
+            next: vec![],
+        }
+    }
+
+    // Returns the configuration in effect at `t` and when it is replaced.
+    pub fn config_at(&self, t: SystemTime) -> (&SourceConfig, Option<SystemTime>) {
+        let mut cur = &self.config;
+        for (update_at, next) in &self.next {
+            if t < *update_at {
+                return (cur, Some(*update_at));
+            }
+            cur = next;
         }
+        (cur, None)
     }
 }
 
@@ -175,25 +310,31 @@ struct SourceNextRepr {
 
 #[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 struct SourceRepr {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    serial: Option<u64>,
     #[serde(default)]
     servers: Vec<Server>,
     #[serde(default)]
     road_warriors: Vec<RoadWarrior>,
-    next: Option<SourceNextRepr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "one_or_many")]
+    next: Vec<SourceNextRepr>,
 }
 
 impl From<Source> for SourceRepr {
     #[inline]
     fn from(v: Source) -> Self {
-        let Source { config, next } = v;
+        let Source {
+            serial,
+            config,
+            next,
+        } = v;
         let SourceConfig {
             servers,
             road_warriors,
         } = config;
-        Self {
-            servers,
-            road_warriors,
-            next: next.map(
+        let next = next
+            .into_iter()
+            .map(
                 #[inline]
                 |next| {
                     let (update_at, next) = next;
@@ -203,25 +344,31 @@ impl From<Source> for SourceRepr {
                         update_at,
                     }
                 },
-            ),
+            )
+            .collect();
+        Self {
+            serial,
+            servers,
+            road_warriors,
+            next,
         }
     }
 }
 
-impl From<SourceRepr> for Source {
+impl TryFrom<SourceRepr> for Source {
+    type Error = &'static str;
+
     #[inline]
-    fn from(v: SourceRepr) -> Self {
+    fn try_from(v: SourceRepr) -> Result<Self, &'static str> {
         let SourceRepr {
+            serial,
             servers,
             road_warriors,
             next,
         } = v;
-        Self {
-            config: SourceConfig {
-                servers,
-                road_warriors,
-            },
-            next: next.map(
+        let next: Vec<_> = next
+            .into_iter()
+            .map(
                 #[inline]
                 |next| {
                     let SourceNextRepr {
@@ -237,8 +384,170 @@ impl From<SourceRepr> for Source {
                         },
                     )
                 },
-            ),
+            )
+            .collect();
+        if next.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err("scheduled configurations must be in ascending order of update_at");
         }
+        Ok(Self {
+            serial,
+            config: SourceConfig {
+                servers,
+                road_warriors,
+            },
+            next,
+        })
+    }
+}
+
+// A source wrapped together with its minisign signature.
+#[derive(serde_derive::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Signed {
+    pub signed: String,
+    pub signature: String,
+}
+
+// The reply to a status request on the control socket.
+#[derive(serde_derive::Serialize)]
+pub struct Status<'a> {
+    pub sources: Vec<SourceStatus<'a>>,
+    pub config: ConfigStatus<'a>,
+    // The metadata of the configured peers that have any.
+    pub peer_info: HashMap<Key, PeerInfo<'a>>,
+    pub scheduled: Vec<ScheduledUpdate<'a>>,
+    pub errors: Vec<ErrorStatus>,
+    // When the interface configuration last changed.
+    #[serde(serialize_with = "serde_utc::serialize_opt")]
+    pub last_change: Option<SystemTime>,
+}
+
+// The interface configuration, without the preshared keys.
+#[derive(serde_derive::Serialize)]
+pub struct ConfigStatus<'a> {
+    pub peers: HashMap<Key, PeerStatus<'a>>,
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct PeerStatus<'a> {
+    pub endpoint: Option<model::Endpoint>,
+    pub has_psk: bool,
+    pub keepalive: u32,
+    pub ipv4: &'a [Ipv4Net],
+    pub ipv6: &'a [Ipv6Net],
+}
+
+impl<'a> From<&'a model::Config> for ConfigStatus<'a> {
+    fn from(c: &'a model::Config) -> Self {
+        let peers = c.peers.iter().map(|(k, p)| {
+            let p = PeerStatus {
+                endpoint: p.endpoint,
+                has_psk: p.psk.is_some(),
+                keepalive: p.keepalive,
+                ipv4: &p.ipv4,
+                ipv6: &p.ipv6,
+            };
+            (*k, p)
+        });
+        Self {
+            peers: peers.collect(),
+        }
+    }
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct SourceStatus<'a> {
+    pub name: &'a str,
+    pub urls: &'a [String],
+    pub serial: Option<u64>,
+    // Whether the data was loaded from the cache rather than fetched.
+    pub from_cache: bool,
+    #[serde(serialize_with = "serde_utc::serialize_opt")]
+    pub last_update: Option<SystemTime>,
+    pub last_error: Option<&'a str>,
+    pub fetch_successes: u64,
+    pub fetch_failures: u64,
+    pub backoff_sec: Option<f64>,
+    #[serde(with = "serde_utc")]
+    pub next_update: SystemTime,
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct ErrorStatus {
+    pub message: String,
+    pub source: String,
+    pub peer: Key,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_name: Option<String>,
+    pub important: bool,
+    // What was done about the error.
+    pub action: &'static str,
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct PeerInfo<'a> {
+    pub source: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    pub tags: &'a [String],
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct ScheduledUpdate<'a> {
+    pub source: &'a str,
+    #[serde(with = "serde_utc")]
+    pub update_at: SystemTime,
+}
+
+// Lists of things that a source could only have one of in earlier versions.
+// A single value is still accepted on its own, and a list of one value is
+// written that way so that earlier versions can read it.
+mod one_or_many {
+    use serde::de::{self, IntoDeserializer};
+    use serde::*;
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    pub fn serialize<T: Serialize, S: Serializer>(v: &[T], ser: S) -> Result<S::Ok, S::Error> {
+        match v {
+            [v] => v.serialize(ser),
+            v => v.serialize(ser),
+        }
+    }
+
+    // Unlike an untagged enum, passes on the error from the value itself.
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Vec<T>, D::Error> {
+        struct OneOrManyVisitor<T>(PhantomData<T>);
+        impl<'de, T: Deserialize<'de>> de::Visitor<'de> for OneOrManyVisitor<T> {
+            type Value = Vec<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a value or a list of values")
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Deserialize::deserialize(de::value::SeqAccessDeserializer::new(seq))
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                T::deserialize(de::value::MapAccessDeserializer::new(map)).map(|v| vec![v])
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                T::deserialize(s.into_deserializer()).map(|v| vec![v])
+            }
+
+            // Earlier versions wrote a missing value as null.
+            fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(vec![])
+            }
+        }
+        de.deserialize_any(OneOrManyVisitor(PhantomData))
     }
 }
 
@@ -255,7 +564,7 @@ mod serde_utc {
         } else {
             let mut buf = [0_u8; 12];
             // FIXME: arrayref needs to silence this per-expression
-            #[allow(clippy::eval_order_dependence)]
+            #[allow(clippy::mixed_read_write_in_expression)]
             let (buf_secs, buf_nanos) = mut_array_refs![&mut buf, 8, 4];
             *buf_secs = t.timestamp().to_be_bytes();
             *buf_nanos = t.timestamp_subsec_nanos().to_be_bytes();
@@ -263,6 +572,31 @@ mod serde_utc {
         }
     }
 
+    pub fn serialize_opt<S: Serializer>(t: &Option<SystemTime>, ser: S) -> Result<S::Ok, S::Error> {
+        struct Time<'a>(&'a SystemTime);
+        impl Serialize for Time<'_> {
+            fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+                serialize(self.0, ser)
+            }
+        }
+        match t {
+            Some(t) => ser.serialize_some(&Time(t)),
+            None => ser.serialize_none(),
+        }
+    }
+
+    pub fn deserialize_opt<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        struct Time(SystemTime);
+        impl<'de> Deserialize<'de> for Time {
+            fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+                deserialize(de).map(Time)
+            }
+        }
+        Option::<Time>::deserialize(de).map(|t| t.map(|t| t.0))
+    }
+
     pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<SystemTime, D::Error> {
         if de.is_human_readable() {
             struct RFC3339Visitor;
@@ -283,11 +617,14 @@ mod serde_utc {
         } else {
             let mut buf = <[u8; 12]>::deserialize(de)?;
             // FIXME: arrayref needs to silence this per-expression
-            #[allow(clippy::eval_order_dependence)]
+            #[allow(clippy::mixed_read_write_in_expression)]
             let (buf_secs, buf_nanos) = array_refs![&mut buf, 8, 4];
             let secs = i64::from_be_bytes(*buf_secs);
             let nanos = u32::from_be_bytes(*buf_nanos);
-            Ok(Utc.timestamp(secs, nanos).into())
+            Utc.timestamp_opt(secs, nanos)
+                .single()
+                .map(SystemTime::from)
+                .ok_or_else(|| de::Error::custom("timestamp out of range"))
         }
     }
 }
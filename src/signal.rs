@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use std::io;
+use std::os::raw::c_int;
+
+#[cfg(target_os = "linux")]
+pub use libc::{SIGHUP, SIGUSR1};
+
+// Never delivered; the values only need to be distinct.
+#[cfg(not(target_os = "linux"))]
+pub const SIGHUP: c_int = 1;
+#[cfg(not(target_os = "linux"))]
+pub const SIGUSR1: c_int = 10;
+
+// Signals are delivered through a pipe so that they can wake up `watch::wait`.
+#[cfg(target_os = "linux")]
+pub struct Signals {
+    fd: std::os::unix::io::OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+static PIPE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+#[cfg(target_os = "linux")]
+extern "C" fn handler(sig: c_int) {
+    use std::sync::atomic::Ordering;
+
+    let fd = PIPE.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+    unsafe {
+        let errno = *libc::__errno_location();
+        let b = sig as u8;
+        libc::write(fd, &b as *const u8 as *const libc::c_void, 1);
+        *libc::__errno_location() = errno;
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Signals {
+    // Only one instance may exist at a time.
+    pub fn new(signals: &[c_int]) -> io::Result<Self> {
+        use std::os::unix::io::{FromRawFd, OwnedFd};
+        use std::sync::atomic::Ordering;
+
+        let mut fds = [-1; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (rd, wr) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let prev = PIPE.swap(fds[1], Ordering::Relaxed);
+        assert!(prev < 0);
+        std::mem::forget(wr);
+
+        for &sig in signals {
+            let mut sa: libc::sigaction = unsafe { std::mem::zeroed() };
+            sa.sa_sigaction = handler as *const () as libc::sighandler_t;
+            sa.sa_flags = libc::SA_RESTART;
+            if unsafe { libc::sigaction(sig, &sa, std::ptr::null_mut()) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Self { fd: rd })
+    }
+
+    #[inline]
+    pub fn as_raw_fd(&self) -> c_int {
+        use std::os::unix::io::AsRawFd;
+        self.fd.as_raw_fd()
+    }
+
+    // Returns the signals received since the last call, without waiting.
+    pub fn pending(&self) -> io::Result<Vec<c_int>> {
+        let mut r: Vec<c_int> = vec![];
+        let mut buf = [0_u8; 64];
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+            if n == 0 {
+                break;
+            }
+            for &b in &buf[..n as usize] {
+                let sig = c_int::from(b);
+                if !r.contains(&sig) {
+                    r.push(sig);
+                }
+            }
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub enum Signals {}
+
+#[cfg(not(target_os = "linux"))]
+impl Signals {
+    #[inline]
+    pub fn new(_signals: &[c_int]) -> io::Result<Self> {
+        Err(io::Error::other("signal handling not supported"))
+    }
+
+    #[inline]
+    pub fn as_raw_fd(&self) -> c_int {
+        match *self {}
+    }
+
+    #[inline]
+    pub fn pending(&self) -> io::Result<Vec<c_int>> {
+        match *self {}
+    }
+}
@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use std::path::PathBuf;
+use std::{env, fs};
+
+// A directory that is removed with everything in it when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        use rand::RngCore;
+        let mut p = env::temp_dir();
+        p.push(format!(
+            "wgconfd-test.{:016x}",
+            rand::thread_rng().next_u64()
+        ));
+        fs::create_dir(&p).unwrap();
+        Self(p)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,302 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use std::io;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// Watches for files being written or replaced. Directories are watched instead of
+// the files themselves so that files replaced by renaming are noticed as well.
+#[cfg(target_os = "linux")]
+pub struct Watcher {
+    fd: std::os::unix::io::OwnedFd,
+    dirs: std::collections::HashMap<i32, PathBuf>,
+    // Maps the path seen by the watch to the path the file was added as.
+    files: std::collections::HashMap<PathBuf, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        use std::os::unix::io::FromRawFd;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { std::os::unix::io::OwnedFd::from_raw_fd(fd) },
+            dirs: std::collections::HashMap::new(),
+            files: std::collections::HashMap::new(),
+        })
+    }
+
+    pub fn add(&mut self, path: &Path) -> io::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::io::AsRawFd;
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+        let dir = match path.parent() {
+            Some(v) if !v.as_os_str().is_empty() => v,
+            _ => Path::new("."),
+        };
+        let c_dir = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid path"))?;
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                self.fd.as_raw_fd(),
+                c_dir.as_ptr(),
+                libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO,
+            )
+        };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, dir.to_owned());
+        self.files.insert(dir.join(name), path.to_owned());
+        Ok(())
+    }
+
+    // Stops watching the files not in `paths`, and the directories that no
+    // longer contain watched files.
+    pub fn retain(&mut self, paths: &[PathBuf]) {
+        use std::os::unix::io::AsRawFd;
+
+        self.files.retain(|_, path| paths.contains(path));
+        let files = &self.files;
+        let fd = self.fd.as_raw_fd();
+        self.dirs.retain(|wd, dir| {
+            if files.keys().any(|f| f.parent() == Some(dir)) {
+                return true;
+            }
+            unsafe { libc::inotify_rm_watch(fd, *wd) };
+            false
+        });
+    }
+
+    #[inline]
+    pub fn as_raw_fd(&self) -> c_int {
+        use std::os::unix::io::AsRawFd;
+        self.fd.as_raw_fd()
+    }
+
+    // Returns the watched files that have changed since the last call, without
+    // waiting.
+    pub fn changed(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut changed = vec![];
+        let mut buf = [0_u8; 4096];
+        loop {
+            let r = unsafe {
+                libc::read(
+                    self.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if r < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+            self.parse_events(&buf[..r as usize], &mut changed);
+        }
+        Ok(changed)
+    }
+
+    fn parse_events(&self, mut buf: &[u8], changed: &mut Vec<PathBuf>) {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        const HDR: usize = 16;
+        while buf.len() >= HDR {
+            let wd = i32::from_ne_bytes(*array_ref![buf, 0, 4]);
+            let len = u32::from_ne_bytes(*array_ref![buf, 12, 4]) as usize;
+            if buf.len() < HDR + len {
+                break;
+            }
+            let name = &buf[HDR..HDR + len];
+            buf = &buf[HDR + len..];
+
+            let name = match name.iter().position(|c| *c == 0) {
+                Some(i) => &name[..i],
+                None => name,
+            };
+            let dir = match self.dirs.get(&wd) {
+                Some(v) => v,
+                None => continue,
+            };
+            let path = match self.files.get(&dir.join(OsStr::from_bytes(name))) {
+                Some(v) => v,
+                None => continue,
+            };
+            if !changed.contains(path) {
+                changed.push(path.clone());
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub enum Watcher {}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    #[inline]
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::other("file watching not supported"))
+    }
+
+    #[inline]
+    pub fn add(&mut self, _path: &Path) -> io::Result<()> {
+        match *self {}
+    }
+
+    #[inline]
+    pub fn retain(&mut self, _paths: &[PathBuf]) {
+        match *self {}
+    }
+
+    #[inline]
+    pub fn as_raw_fd(&self) -> c_int {
+        match *self {}
+    }
+
+    #[inline]
+    pub fn changed(&mut self) -> io::Result<Vec<PathBuf>> {
+        match *self {}
+    }
+}
+
+// Waits until one of `fds` becomes readable or until `deadline`.
+#[cfg(target_os = "linux")]
+pub fn wait(fds: &[c_int], deadline: Instant) -> io::Result<()> {
+    let now = Instant::now();
+    let timeout = if deadline > now {
+        let d = deadline - now;
+        (d.as_millis() + u128::from(d.subsec_nanos() % 1_000_000 != 0)).min(c_int::MAX as u128)
+            as c_int
+    } else {
+        0
+    };
+
+    let mut pfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let r = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout) };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn wait(_fds: &[c_int], deadline: Instant) -> io::Result<()> {
+    let now = Instant::now();
+    if deadline > now {
+        std::thread::sleep(deadline - now);
+    }
+    Ok(())
+}
+
+// Returns the local path of a `file://` URL.
+pub fn file_url_path(url: &str) -> Option<PathBuf> {
+    let rest = url.strip_prefix("file://")?;
+    let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !rest.starts_with('/') {
+        return None;
+    }
+
+    let mut path = Vec::with_capacity(rest.len());
+    let mut bytes = rest.bytes();
+    while let Some(c) = bytes.next() {
+        if c != b'%' {
+            path.push(c);
+            continue;
+        }
+        let hi = char::from(bytes.next()?).to_digit(16)?;
+        let lo = char::from(bytes.next()?).to_digit(16)?;
+        path.push((hi * 16 + lo) as u8);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+        Some(OsString::from_vec(path).into())
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8(path).ok().map(PathBuf::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{file_url_path, wait, Watcher};
+    use crate::testutil::TempDir;
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_file_url_path() {
+        assert_eq!(
+            file_url_path("file:///etc/wireguard/a.json").unwrap(),
+            Path::new("/etc/wireguard/a.json")
+        );
+        assert_eq!(
+            file_url_path("file://localhost/a%20b.json").unwrap(),
+            Path::new("/a b.json")
+        );
+        assert!(file_url_path("https://example.org/a.json").is_none());
+        assert!(file_url_path("file://example.org/a.json").is_none());
+        assert!(file_url_path("file:///a%2").is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watcher() {
+        let dir = TempDir::new();
+        let dir = &dir.0;
+        let a = dir.join("a.json");
+        let b = dir.join("b.json");
+
+        let mut w = Watcher::new().unwrap();
+        w.add(&a).unwrap();
+        assert!(w.changed().unwrap().is_empty());
+
+        fs::write(&b, "b").unwrap();
+        fs::write(&a, "a").unwrap();
+        let tmp = dir.join("a.json.tmp");
+        fs::write(&tmp, "a").unwrap();
+        fs::rename(&tmp, &a).unwrap();
+        wait(&[w.as_raw_fd()], Instant::now() + Duration::from_secs(5)).unwrap();
+        assert_eq!(w.changed().unwrap(), vec![a.clone()]);
+
+        // Files that are no longer wanted are not reported.
+        w.add(&b).unwrap();
+        w.retain(std::slice::from_ref(&a));
+        fs::write(&b, "b").unwrap();
+        fs::write(&a, "a").unwrap();
+        wait(&[w.as_raw_fd()], Instant::now() + Duration::from_secs(5)).unwrap();
+        assert_eq!(w.changed().unwrap(), vec![a]);
+        w.retain(&[]);
+        assert!(w.dirs.is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use crate::{fileutil, model};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use std::{env, fmt, io};
+
+pub struct Device {
+    ifname: OsString,
+    tmpdir: PathBuf,
+}
+
+impl Device {
+    #[inline]
+    pub fn open(ifname: OsString, tmpdir: PathBuf) -> io::Result<Self> {
+        let dev = Self { ifname, tmpdir };
+        let _ = super::Device::get_public_key(&dev)?;
+        Ok(dev)
+    }
+
+    fn wg_command() -> Command {
+        let wg = match env::var_os("WG") {
+            None => OsString::new(),
+            Some(v) => v,
+        };
+
+        Command::new(if wg.is_empty() {
+            OsStr::new("wg")
+        } else {
+            wg.as_os_str()
+        })
+    }
+}
+
+impl super::Device for Device {
+    fn get_public_key(&self) -> io::Result<model::Key> {
+        let mut proc = Self::wg_command();
+        proc.stdin(Stdio::null());
+        proc.stdout(Stdio::piped());
+        proc.arg("show");
+        proc.arg(&self.ifname);
+        proc.arg("public-key");
+
+        let r = proc.output()?;
+        if !r.status.success() {
+            return Err(io::Error::other("child process failed"));
+        }
+
+        let mut out = r.stdout;
+        if out.last().copied() == Some(b'\n') {
+            out.pop();
+        }
+        model::Key::from_base64(&out)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid public key"))
+    }
+
+    fn get_peers(&self) -> io::Result<model::Config> {
+        let mut proc = Self::wg_command();
+        proc.stdin(Stdio::null());
+        proc.stdout(Stdio::piped());
+        proc.arg("show");
+        proc.arg(&self.ifname);
+        proc.arg("dump");
+
+        let r = proc.output()?;
+        if !r.status.success() {
+            return Err(io::Error::other("child process failed"));
+        }
+
+        let out = String::from_utf8(r.stdout)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid `wg show' output"))?;
+        let mut config = model::Config::empty();
+        for line in out.lines().skip(1) {
+            let (pubkey, peer) = parse_dump_peer(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid `wg show' output")
+            })?;
+            config.peers.insert(pubkey, peer);
+        }
+        Ok(config)
+    }
+
+    fn get_handshakes(&self) -> io::Result<HashMap<model::Key, SystemTime>> {
+        let mut proc = Self::wg_command();
+        proc.stdin(Stdio::null());
+        proc.stdout(Stdio::piped());
+        proc.arg("show");
+        proc.arg(&self.ifname);
+        proc.arg("latest-handshakes");
+
+        let r = proc.output()?;
+        if !r.status.success() {
+            return Err(io::Error::other("child process failed"));
+        }
+
+        let out = String::from_utf8(r.stdout)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid `wg show' output"))?;
+        let mut handshakes = HashMap::new();
+        for line in out.lines() {
+            let (pubkey, t) = parse_handshake(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid `wg show' output")
+            })?;
+            if let Some(t) = t {
+                handshakes.insert(pubkey, t);
+            }
+        }
+        Ok(handshakes)
+    }
+
+    fn apply_diff(&mut self, old: &model::Config, new: &model::Config) -> io::Result<()> {
+        let mut config = String::new();
+
+        for (pubkey, conf) in &new.peers {
+            let old_endpoint;
+            let old_psk;
+            if let Some(old_peer) = old.peers.get(pubkey) {
+                if *old_peer == *conf {
+                    continue;
+                }
+                old_endpoint = old_peer.endpoint;
+                old_psk = old_peer.psk.as_ref();
+            } else {
+                old_endpoint = None;
+                old_psk = None;
+            }
+
+            use fmt::Write;
+            write!(
+                config,
+                "[Peer]\nPublicKey={}\nPersistentKeepalive={}\nAllowedIPs",
+                pubkey, conf.keepalive
+            )
+            .unwrap();
+            let mut delim = '=';
+            for ip in &conf.ipv4 {
+                config.push(delim);
+                delim = ',';
+                write!(config, "{}", ip).unwrap();
+            }
+            for ip in &conf.ipv6 {
+                config.push(delim);
+                delim = ',';
+                write!(config, "{}", ip).unwrap();
+            }
+            config.push('\n');
+
+            if old_endpoint != conf.endpoint {
+                if let Some(ref endpoint) = conf.endpoint {
+                    writeln!(config, "Endpoint={}", endpoint).unwrap();
+                }
+            }
+
+            if old_psk != conf.psk.as_ref() {
+                config.push_str("PresharedKey=");
+                if let Some(psk) = conf.psk.as_ref() {
+                    writeln!(config, "{}", psk).unwrap();
+                    config.push('\n');
+                } else {
+                    config.push_str("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n");
+                }
+            }
+        }
+
+        {
+            let mut config_file = fileutil::Writer::new_in(&self.tmpdir)?;
+            io::Write::write_all(config_file.file(), config.as_bytes())?;
+            let config_file = config_file.done();
+
+            let mut proc = Self::wg_command();
+            proc.stdin(Stdio::null());
+            proc.stdout(Stdio::null());
+            proc.arg("addconf");
+            proc.arg(&self.ifname);
+            proc.arg(config_file.path());
+
+            let r = proc.status()?;
+            if !r.success() {
+                return Err(io::Error::other("`wg setconf' process failed"));
+            }
+        }
+
+        let mut proc = Self::wg_command();
+        let mut any_removed = false;
+        proc.stdin(Stdio::null());
+        proc.stdout(Stdio::null());
+        proc.arg("set");
+        proc.arg(&self.ifname);
+
+        for pubkey in old.peers.keys() {
+            if new.peers.contains_key(pubkey) {
+                continue;
+            }
+            any_removed = true;
+            proc.arg("peer");
+            proc.arg(pubkey.to_string());
+            proc.arg("remove");
+        }
+
+        if any_removed {
+            let r = proc.status()?;
+            if !r.success() {
+                return Err(io::Error::other("`wg set' process failed"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Parses a peer line of `wg show IFNAME dump`:
+// public-key, preshared-key, endpoint, allowed-ips, latest-handshake,
+// transfer-rx, transfer-tx, persistent-keepalive
+fn parse_dump_peer(line: &str) -> Option<(model::Key, model::Peer)> {
+    let mut fields = line.split('\t');
+    let pubkey = model::Key::from_str(fields.next()?).ok()?;
+
+    let psk = match fields.next()? {
+        "(none)" => None,
+        v => Some(model::Secret::from_key(model::Key::from_str(v).ok()?)),
+    };
+
+    let endpoint = match fields.next()? {
+        "(none)" => None,
+        v => Some(model::Endpoint::from_str(v).ok()?),
+    };
+
+    let mut ipv4 = vec![];
+    let mut ipv6 = vec![];
+    match fields.next()? {
+        "(none)" => {}
+        v => {
+            for ip in v.split(',') {
+                if let Ok(ip) = model::Ipv4Net::from_str(ip) {
+                    ipv4.push(ip);
+                } else {
+                    ipv6.push(model::Ipv6Net::from_str(ip).ok()?);
+                }
+            }
+        }
+    }
+
+    let keepalive = match fields.nth(3)? {
+        "off" => 0,
+        v => u32::from_str(v).ok()?,
+    };
+
+    Some((
+        pubkey,
+        model::Peer {
+            endpoint,
+            psk,
+            keepalive,
+            ipv4,
+            ipv6,
+        },
+    ))
+}
+
+// Parses a line of `wg show IFNAME latest-handshakes`. The time is 0 for peers
+// that have not had a handshake.
+fn parse_handshake(line: &str) -> Option<(model::Key, Option<SystemTime>)> {
+    let mut fields = line.split('\t');
+    let pubkey = model::Key::from_str(fields.next()?).ok()?;
+    let t = match u64::from_str(fields.next()?).ok()? {
+        0 => None,
+        v => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(v)),
+    };
+    Some((pubkey, t))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_dump_peer, parse_handshake};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_parse_dump_peer() {
+        let (key, peer) = parse_dump_peer(
+            "hw0U7vI2rhjG9mQ34CUKO6M4dIF9e8ofKj5N6cAPtwY=\t(none)\t198.51.100.66:656\t\
+             10.1.2.0/24,2001:db8::/32\t0\t0\t0\t25",
+        )
+        .unwrap();
+        assert_eq!(
+            key.to_string(),
+            "hw0U7vI2rhjG9mQ34CUKO6M4dIF9e8ofKj5N6cAPtwY="
+        );
+        assert_eq!(peer.endpoint.unwrap().to_string(), "198.51.100.66:656");
+        assert!(peer.psk.is_none());
+        assert_eq!(peer.keepalive, 25);
+        assert_eq!(peer.ipv4.len(), 1);
+        assert_eq!(peer.ipv6.len(), 1);
+
+        let (_, peer) = parse_dump_peer(
+            "hw0U7vI2rhjG9mQ34CUKO6M4dIF9e8ofKj5N6cAPtwY=\t\
+             nlFVtJrOwR2sVJji6NQjXnv//GVUK5W9T7ftkSnYPA8=\t(none)\t(none)\t0\t0\t0\toff",
+        )
+        .unwrap();
+        assert!(peer.endpoint.is_none());
+        assert!(peer.psk.is_some());
+        assert_eq!(peer.keepalive, 0);
+        assert!(peer.ipv4.is_empty());
+
+        assert!(parse_dump_peer("garbage").is_none());
+    }
+
+    #[test]
+    fn test_parse_handshake() {
+        let (_, t) =
+            parse_handshake("hw0U7vI2rhjG9mQ34CUKO6M4dIF9e8ofKj5N6cAPtwY=\t1571229800").unwrap();
+        assert_eq!(
+            t,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1571229800))
+        );
+        let (_, t) = parse_handshake("hw0U7vI2rhjG9mQ34CUKO6M4dIF9e8ofKj5N6cAPtwY=\t0").unwrap();
+        assert!(t.is_none());
+        assert!(parse_handshake("garbage").is_none());
+    }
+}
@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use crate::model;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Change {
+    Set(model::Key, model::Peer),
+    Remove(model::Key),
+}
+
+struct Inner {
+    public_key: model::Key,
+    peers: model::Config,
+    handshakes: HashMap<model::Key, SystemTime>,
+    changes: Vec<Change>,
+}
+
+// An in-memory interface. Clones share the same state, so a test can keep one
+// handle while the manager owns another.
+#[derive(Clone)]
+pub struct Device {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Device {
+    pub fn new(public_key: model::Key) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Inner {
+                public_key,
+                peers: model::Config::empty(),
+                handshakes: HashMap::new(),
+                changes: vec![],
+            })),
+        }
+    }
+
+    #[inline]
+    pub fn peers(&self) -> model::Config {
+        self.inner.borrow().peers.clone()
+    }
+
+    // Returns the changes applied since the last call.
+    #[inline]
+    pub fn take_changes(&self) -> Vec<Change> {
+        std::mem::take(&mut self.inner.borrow_mut().changes)
+    }
+
+    // Modifies the interface behind the manager's back.
+    pub fn set_peer(&self, key: model::Key, peer: Option<model::Peer>) {
+        let mut inner = self.inner.borrow_mut();
+        match peer {
+            Some(peer) => inner.peers.peers.insert(key, peer),
+            None => inner.peers.peers.remove(&key),
+        };
+    }
+
+    #[inline]
+    pub fn set_handshake(&self, key: model::Key, t: SystemTime) {
+        self.inner.borrow_mut().handshakes.insert(key, t);
+    }
+}
+
+impl super::Device for Device {
+    fn get_public_key(&self) -> io::Result<model::Key> {
+        Ok(self.inner.borrow().public_key)
+    }
+
+    fn get_peers(&self) -> io::Result<model::Config> {
+        Ok(self.peers())
+    }
+
+    fn get_handshakes(&self) -> io::Result<HashMap<model::Key, SystemTime>> {
+        Ok(self.inner.borrow().handshakes.clone())
+    }
+
+    fn apply_diff(&mut self, old: &model::Config, new: &model::Config) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let mut changes = vec![];
+
+        let mut keys: Vec<_> = new.peers.keys().collect();
+        keys.sort();
+        for key in keys {
+            let peer = &new.peers[key];
+            if old.peers.get(key) == Some(peer) {
+                continue;
+            }
+            // Like WireGuard, an allowed IP stays only on the peer it was
+            // given to last.
+            for (k, other) in inner.peers.peers.iter_mut() {
+                if k != key {
+                    other.ipv4.retain(|ip| !peer.ipv4.contains(ip));
+                    other.ipv6.retain(|ip| !peer.ipv6.contains(ip));
+                }
+            }
+            inner.peers.peers.insert(*key, peer.clone());
+            changes.push(Change::Set(*key, peer.clone()));
+        }
+
+        let mut keys: Vec<_> = old.peers.keys().collect();
+        keys.sort();
+        for key in keys {
+            if new.peers.contains_key(key) {
+                continue;
+            }
+            inner.peers.peers.remove(key);
+            changes.push(Change::Remove(*key));
+        }
+
+        inner.changes.extend(changes);
+        Ok(())
+    }
+}
@@ -0,0 +1,808 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+//
+// Copyright 2019 Hristo Venev
+
+use crate::model;
+use std::cell::Cell;
+use std::collections::{hash_map, HashMap};
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, SystemTime};
+use std::{io, mem};
+
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_MULTI: u16 = 0x2;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_DUMP: u16 = 0x300;
+
+const NLA_F_NESTED: u16 = 0x8000;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const WG_GENL_NAME: &[u8] = b"wireguard\0";
+const WG_GENL_VERSION: u8 = 1;
+
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_PEERS: u16 = 8;
+
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+
+const WGPEER_F_REMOVE_ME: u32 = 0x1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 0x2;
+
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+const NLMSG_HDRLEN: usize = 16;
+const GENL_HDRLEN: usize = 4;
+
+// The size that messages setting up peers are kept under, as in wg(8).
+const MAX_MESSAGE_LEN: usize = 8192;
+// An IPv6 allowed IP entry, with its family and mask.
+const MAX_ALLOWED_IP_LEN: usize = 4 + 8 + 20 + 8;
+
+#[inline]
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(ty: u16, flags: u16, cmd: u8, version: u8) -> Self {
+        let mut buf = Vec::with_capacity(256);
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&ty.to_ne_bytes());
+        buf.extend_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&[cmd, version, 0, 0]);
+        Self { buf }
+    }
+
+    fn attr(&mut self, ty: u16, data: &[u8]) {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    #[inline]
+    fn attr_u8(&mut self, ty: u16, v: u8) {
+        self.attr(ty, &[v]);
+    }
+
+    #[inline]
+    fn attr_u16(&mut self, ty: u16, v: u16) {
+        self.attr(ty, &v.to_ne_bytes());
+    }
+
+    #[inline]
+    fn attr_u32(&mut self, ty: u16, v: u32) {
+        self.attr(ty, &v.to_ne_bytes());
+    }
+
+    fn attr_str(&mut self, ty: u16, v: &OsStr) {
+        let mut data = Vec::with_capacity(v.len() + 1);
+        data.extend_from_slice(v.as_bytes());
+        data.push(0);
+        self.attr(ty, &data);
+    }
+
+    #[inline]
+    fn begin_nested(&mut self, ty: u16) -> usize {
+        let pos = self.buf.len();
+        self.buf.extend_from_slice(&[0; 2]);
+        self.buf
+            .extend_from_slice(&(ty | NLA_F_NESTED).to_ne_bytes());
+        pos
+    }
+
+    #[inline]
+    fn end_nested(&mut self, pos: usize) -> io::Result<()> {
+        let len = u16::try_from(self.buf.len() - pos).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "netlink attribute too long")
+        })?;
+        *array_mut_ref![self.buf, pos, 2] = len.to_ne_bytes();
+        Ok(())
+    }
+
+    fn finish(&mut self, seq: u32) -> &[u8] {
+        let len = self.buf.len() as u32;
+        *array_mut_ref![self.buf, 0, 4] = len.to_ne_bytes();
+        *array_mut_ref![self.buf, 8, 4] = seq.to_ne_bytes();
+        &self.buf
+    }
+}
+
+struct Attrs<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Attrs<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.len() < 4 {
+            return None;
+        }
+        let len = usize::from(u16::from_ne_bytes(*array_ref![self.buf, 0, 2]));
+        let ty = u16::from_ne_bytes(*array_ref![self.buf, 2, 2]);
+        if len < 4 || len > self.buf.len() {
+            self.buf = &[];
+            return None;
+        }
+        let data = &self.buf[4..len];
+        self.buf = &self.buf[align(len).min(self.buf.len())..];
+        Some((ty & NLA_TYPE_MASK, data))
+    }
+}
+
+#[inline]
+fn attrs(buf: &[u8]) -> Attrs<'_> {
+    Attrs { buf }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct Socket {
+    fd: OwnedFd,
+    seq: Cell<u32>,
+}
+
+impl Socket {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let r = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd,
+            seq: Cell::new(0),
+        })
+    }
+
+    fn send(&self, msg: &mut Message) -> io::Result<u32> {
+        let seq = self.seq.get().wrapping_add(1);
+        self.seq.set(seq);
+        let buf = msg.finish(seq);
+        let r = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(seq)
+    }
+
+    fn recv(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.clear();
+        loop {
+            let r = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.capacity(),
+                    libc::MSG_PEEK | libc::MSG_TRUNC,
+                )
+            };
+            if r < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            let len = r as usize;
+            if len > buf.capacity() {
+                buf.reserve(len);
+                continue;
+            }
+
+            let r = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.capacity(),
+                    0,
+                )
+            };
+            if r < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            unsafe { buf.set_len(r as usize) };
+            return Ok(());
+        }
+    }
+
+    // Sends a request and calls `f` with the payload of every reply, after the
+    // generic netlink header. Returns once the kernel acknowledges or finishes
+    // the dump.
+    fn request(
+        &self,
+        msg: &mut Message,
+        mut f: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let seq = self.send(msg)?;
+        let mut buf = Vec::with_capacity(16384);
+        loop {
+            self.recv(&mut buf)?;
+            let mut data = &buf[..];
+            while data.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes(*array_ref![data, 0, 4]) as usize;
+                let ty = u16::from_ne_bytes(*array_ref![data, 4, 2]);
+                let flags = u16::from_ne_bytes(*array_ref![data, 6, 2]);
+                let msg_seq = u32::from_ne_bytes(*array_ref![data, 8, 4]);
+                if len < NLMSG_HDRLEN || len > data.len() {
+                    return Err(invalid_data("truncated netlink message"));
+                }
+                let payload = &data[NLMSG_HDRLEN..len];
+                data = &data[align(len).min(data.len())..];
+
+                if msg_seq != seq {
+                    continue;
+                }
+                match ty {
+                    NLMSG_ERROR => {
+                        if payload.len() < 4 {
+                            return Err(invalid_data("truncated netlink error"));
+                        }
+                        let errno = i32::from_ne_bytes(*array_ref![payload, 0, 4]);
+                        if errno == 0 {
+                            return Ok(());
+                        }
+                        return Err(io::Error::from_raw_os_error(-errno));
+                    }
+                    NLMSG_DONE => return Ok(()),
+                    _ => {
+                        if payload.len() < GENL_HDRLEN {
+                            return Err(invalid_data("truncated generic netlink message"));
+                        }
+                        f(&payload[GENL_HDRLEN..])?;
+                        if flags & NLM_F_MULTI == 0 {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn resolve_family(&self, name: &[u8]) -> io::Result<u16> {
+        let mut msg = Message::new(GENL_ID_CTRL, 0, CTRL_CMD_GETFAMILY, 1);
+        msg.attr(CTRL_ATTR_FAMILY_NAME, name);
+
+        let mut id = None;
+        self.request(&mut msg, |data| {
+            for (ty, data) in attrs(data) {
+                if ty == CTRL_ATTR_FAMILY_ID && data.len() == 2 {
+                    id = Some(u16::from_ne_bytes(*array_ref![data, 0, 2]));
+                }
+            }
+            Ok(())
+        })?;
+        id.ok_or_else(|| invalid_data("generic netlink family id missing"))
+    }
+}
+
+pub struct Device {
+    ifname: OsString,
+    sock: Socket,
+    family: u16,
+}
+
+impl Device {
+    pub fn open(ifname: OsString) -> io::Result<Self> {
+        let sock = Socket::open()?;
+        let family = sock.resolve_family(WG_GENL_NAME).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("WireGuard netlink family unavailable: {}", e),
+            )
+        })?;
+        let dev = Self {
+            ifname,
+            sock,
+            family,
+        };
+        let _ = super::Device::get_public_key(&dev)?;
+        Ok(dev)
+    }
+
+    #[inline]
+    fn message(&self, flags: u16, cmd: u8) -> Message {
+        let mut msg = Message::new(self.family, flags, cmd, WG_GENL_VERSION);
+        msg.attr_str(WGDEVICE_A_IFNAME, &self.ifname);
+        msg
+    }
+}
+
+impl super::Device for Device {
+    fn get_public_key(&self) -> io::Result<model::Key> {
+        let mut msg = self.message(NLM_F_DUMP, WG_CMD_GET_DEVICE);
+
+        let mut key = None;
+        self.sock.request(&mut msg, |data| {
+            for (ty, data) in attrs(data) {
+                if ty == WGDEVICE_A_PUBLIC_KEY && data.len() == 32 {
+                    key = Some(model::Key::from_bytes(*array_ref![data, 0, 32]));
+                }
+            }
+            Ok(())
+        })?;
+        key.ok_or_else(|| invalid_data("interface has no public key"))
+    }
+
+    fn get_peers(&self) -> io::Result<model::Config> {
+        let mut msg = self.message(NLM_F_DUMP, WG_CMD_GET_DEVICE);
+
+        let mut config = model::Config::empty();
+        self.sock.request(&mut msg, |data| {
+            for (ty, data) in attrs(data) {
+                if ty != WGDEVICE_A_PEERS {
+                    continue;
+                }
+                for (_, data) in attrs(data) {
+                    parse_peer(&mut config, data)?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(config)
+    }
+
+    fn get_handshakes(&self) -> io::Result<HashMap<model::Key, SystemTime>> {
+        let mut msg = self.message(NLM_F_DUMP, WG_CMD_GET_DEVICE);
+
+        let mut r = HashMap::new();
+        self.sock.request(&mut msg, |data| {
+            for (ty, data) in attrs(data) {
+                if ty != WGDEVICE_A_PEERS {
+                    continue;
+                }
+                for (_, data) in attrs(data) {
+                    if let Some((pubkey, t)) = parse_handshake(data) {
+                        r.insert(pubkey, t);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        Ok(r)
+    }
+
+    fn apply_diff(&mut self, old: &model::Config, new: &model::Config) -> io::Result<()> {
+        for (pubkey, conf) in &new.peers {
+            let old_peer = old.peers.get(pubkey);
+            if old_peer == Some(conf) {
+                continue;
+            }
+
+            let msgs = set_peer_messages(
+                || self.message(NLM_F_ACK, WG_CMD_SET_DEVICE),
+                pubkey,
+                old_peer,
+                conf,
+            )?;
+            for mut msg in msgs {
+                self.sock
+                    .request(&mut msg, |_| Ok(()))
+                    .map_err(|e| peer_error(pubkey, e))?;
+            }
+        }
+
+        for pubkey in old.peers.keys() {
+            if new.peers.contains_key(pubkey) {
+                continue;
+            }
+
+            let mut msg = self.message(NLM_F_ACK, WG_CMD_SET_DEVICE);
+            let nests = begin_peer(&mut msg, pubkey);
+            msg.attr_u32(WGPEER_A_FLAGS, WGPEER_F_REMOVE_ME);
+            end_peer(&mut msg, nests)?;
+
+            self.sock
+                .request(&mut msg, |_| Ok(()))
+                .map_err(|e| peer_error(pubkey, e))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn begin_peer(msg: &mut Message, pubkey: &model::Key) -> (usize, usize) {
+    let peers = msg.begin_nested(WGDEVICE_A_PEERS);
+    let peer = msg.begin_nested(0);
+    msg.attr(WGPEER_A_PUBLIC_KEY, pubkey.as_bytes());
+    (peers, peer)
+}
+
+#[inline]
+fn end_peer(msg: &mut Message, (peers, peer): (usize, usize)) -> io::Result<()> {
+    msg.end_nested(peer)?;
+    msg.end_nested(peers)
+}
+
+// Encodes the messages that configure a peer. As in wg(8), allowed IPs that do
+// not fit in one message are continued in the next ones, and only the first
+// message replaces the allowed IPs the peer already had.
+fn set_peer_messages(
+    mut new: impl FnMut() -> Message,
+    pubkey: &model::Key,
+    old_peer: Option<&model::Peer>,
+    conf: &model::Peer,
+) -> io::Result<Vec<Message>> {
+    let keepalive = u16::try_from(conf.keepalive).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("keepalive for [{}] out of range", pubkey),
+        )
+    })?;
+
+    let mut msgs = vec![];
+    let mut msg = new();
+    let mut nests = begin_peer(&mut msg, pubkey);
+    msg.attr_u32(WGPEER_A_FLAGS, WGPEER_F_REPLACE_ALLOWEDIPS);
+    msg.attr_u16(WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL, keepalive);
+
+    if old_peer.and_then(|p| p.endpoint) != conf.endpoint {
+        if let Some(ref endpoint) = conf.endpoint {
+            msg.attr(WGPEER_A_ENDPOINT, &sockaddr(endpoint));
+        }
+    }
+
+    if old_peer.and_then(|p| p.psk.as_ref()) != conf.psk.as_ref() {
+        match conf.psk {
+            Some(ref psk) => msg.attr(WGPEER_A_PRESHARED_KEY, psk.as_bytes()),
+            None => msg.attr(WGPEER_A_PRESHARED_KEY, &[0; 32]),
+        }
+    }
+
+    let mut ips = msg.begin_nested(WGPEER_A_ALLOWEDIPS);
+    let ipv4 = conf.ipv4.iter().map(|ip| {
+        let addr = ip.address().octets().to_vec();
+        (libc::AF_INET, addr, ip.prefix_len())
+    });
+    let ipv6 = conf.ipv6.iter().map(|ip| {
+        let addr = ip.address().octets().to_vec();
+        (libc::AF_INET6, addr, ip.prefix_len())
+    });
+    for (family, addr, cidr) in ipv4.chain(ipv6) {
+        if msg.buf.len() + MAX_ALLOWED_IP_LEN > MAX_MESSAGE_LEN {
+            msg.end_nested(ips)?;
+            end_peer(&mut msg, nests)?;
+            msgs.push(mem::replace(&mut msg, new()));
+            nests = begin_peer(&mut msg, pubkey);
+            ips = msg.begin_nested(WGPEER_A_ALLOWEDIPS);
+        }
+        let ent = msg.begin_nested(0);
+        msg.attr_u16(WGALLOWEDIP_A_FAMILY, family as u16);
+        msg.attr(WGALLOWEDIP_A_IPADDR, &addr);
+        msg.attr_u8(WGALLOWEDIP_A_CIDR_MASK, cidr);
+        msg.end_nested(ent)?;
+    }
+    msg.end_nested(ips)?;
+    end_peer(&mut msg, nests)?;
+    msgs.push(msg);
+    Ok(msgs)
+}
+
+fn peer_error(pubkey: &model::Key, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("failed to configure peer [{}]: {}", pubkey, e),
+    )
+}
+
+// A peer may be split across several messages of a dump, in which case it is
+// repeated with the remaining allowed IPs.
+fn parse_peer(config: &mut model::Config, data: &[u8]) -> io::Result<()> {
+    let mut pubkey = None;
+    let mut psk = None;
+    let mut endpoint = None;
+    let mut keepalive = 0;
+    let mut ipv4 = vec![];
+    let mut ipv6 = vec![];
+
+    for (ty, data) in attrs(data) {
+        match ty {
+            WGPEER_A_PUBLIC_KEY if data.len() == 32 => {
+                pubkey = Some(model::Key::from_bytes(*array_ref![data, 0, 32]));
+            }
+            WGPEER_A_PRESHARED_KEY if data.len() == 32 => {
+                let key = *array_ref![data, 0, 32];
+                if key != [0; 32] {
+                    psk = Some(model::Secret::from_key(model::Key::from_bytes(key)));
+                }
+            }
+            WGPEER_A_ENDPOINT => {
+                endpoint = parse_sockaddr(data);
+            }
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL if data.len() == 2 => {
+                keepalive = u32::from(u16::from_ne_bytes(*array_ref![data, 0, 2]));
+            }
+            WGPEER_A_ALLOWEDIPS => {
+                for (_, data) in attrs(data) {
+                    parse_allowed_ip(&mut ipv4, &mut ipv6, data)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let pubkey = pubkey.ok_or_else(|| invalid_data("peer without public key"))?;
+    match config.peers.entry(pubkey) {
+        hash_map::Entry::Occupied(ent) => {
+            let ent = ent.into_mut();
+            ent.ipv4.extend(ipv4);
+            ent.ipv6.extend(ipv6);
+        }
+        hash_map::Entry::Vacant(ent) => {
+            ent.insert(model::Peer {
+                endpoint,
+                psk,
+                keepalive,
+                ipv4,
+                ipv6,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn parse_handshake(data: &[u8]) -> Option<(model::Key, SystemTime)> {
+    let mut pubkey = None;
+    let mut t = None;
+    for (ty, data) in attrs(data) {
+        match ty {
+            WGPEER_A_PUBLIC_KEY if data.len() == 32 => {
+                pubkey = Some(model::Key::from_bytes(*array_ref![data, 0, 32]));
+            }
+            // struct __kernel_timespec
+            WGPEER_A_LAST_HANDSHAKE_TIME if data.len() == 16 => {
+                let sec = i64::from_ne_bytes(*array_ref![data, 0, 8]);
+                let nsec = i64::from_ne_bytes(*array_ref![data, 8, 8]);
+                if sec > 0 && (0..1_000_000_000).contains(&nsec) {
+                    t = Some(SystemTime::UNIX_EPOCH + Duration::new(sec as u64, nsec as u32));
+                }
+            }
+            _ => {}
+        }
+    }
+    Some((pubkey?, t?))
+}
+
+fn parse_allowed_ip(
+    ipv4: &mut Vec<model::Ipv4Net>,
+    ipv6: &mut Vec<model::Ipv6Net>,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut family = None;
+    let mut addr: &[u8] = &[];
+    let mut cidr = None;
+    for (ty, data) in attrs(data) {
+        match ty {
+            WGALLOWEDIP_A_FAMILY if data.len() == 2 => {
+                family = Some(i32::from(u16::from_ne_bytes(*array_ref![data, 0, 2])));
+            }
+            WGALLOWEDIP_A_IPADDR => addr = data,
+            WGALLOWEDIP_A_CIDR_MASK if data.len() == 1 => cidr = Some(data[0]),
+            _ => {}
+        }
+    }
+
+    let cidr = cidr.ok_or_else(|| invalid_data("allowed IP without mask"))?;
+    match family {
+        Some(libc::AF_INET) if addr.len() == 4 => {
+            let addr = model::Ipv4Addr::from(*array_ref![addr, 0, 4]);
+            ipv4.push(
+                model::Ipv4Net::new(addr, cidr).map_err(|_| invalid_data("invalid allowed IP"))?,
+            );
+        }
+        Some(libc::AF_INET6) if addr.len() == 16 => {
+            let addr = model::Ipv6Addr::from(*array_ref![addr, 0, 16]);
+            ipv6.push(
+                model::Ipv6Net::new(addr, cidr).map_err(|_| invalid_data("invalid allowed IP"))?,
+            );
+        }
+        _ => return Err(invalid_data("invalid allowed IP")),
+    }
+    Ok(())
+}
+
+fn parse_sockaddr(data: &[u8]) -> Option<model::Endpoint> {
+    if data.len() < 2 {
+        return None;
+    }
+    let family = i32::from(u16::from_ne_bytes(*array_ref![data, 0, 2]));
+    if family == libc::AF_INET && data.len() >= 8 {
+        let port = u16::from_be_bytes(*array_ref![data, 2, 2]);
+        let addr = model::Ipv4Addr::from(*array_ref![data, 4, 4]);
+        Some(SocketAddr::from((addr, port)).into())
+    } else if family == libc::AF_INET6 && data.len() >= 24 {
+        let port = u16::from_be_bytes(*array_ref![data, 2, 2]);
+        let addr = model::Ipv6Addr::from(*array_ref![data, 8, 16]);
+        Some(SocketAddr::from((addr, port)).into())
+    } else {
+        None
+    }
+}
+
+fn sockaddr(endpoint: &model::Endpoint) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(28);
+    if let Some(ipv4) = endpoint.ipv4_address() {
+        buf.extend_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+        buf.extend_from_slice(&endpoint.port().to_be_bytes());
+        buf.extend_from_slice(&ipv4.octets());
+        buf.extend_from_slice(&[0; 8]);
+    } else {
+        buf.extend_from_slice(&(libc::AF_INET6 as u16).to_ne_bytes());
+        buf.extend_from_slice(&endpoint.port().to_be_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&endpoint.ipv6_address().octets());
+        buf.extend_from_slice(&[0; 4]);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Endpoint;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_nested_attrs() {
+        let mut msg = Message::new(0x20, 0, 1, 1);
+        msg.attr_u8(1, 5);
+        let pos = msg.begin_nested(2);
+        msg.attr_u16(3, 7);
+        msg.attr(4, b"abcde");
+        msg.end_nested(pos).unwrap();
+        let buf = msg.finish(1).to_vec();
+        assert_eq!(buf.len() % 4, 0);
+        assert_eq!(
+            u32::from_ne_bytes(*array_ref![buf, 0, 4]) as usize,
+            buf.len()
+        );
+
+        let top: Vec<_> = attrs(&buf[20..]).collect();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0], (1, &[5][..]));
+        assert_eq!(top[1].0, 2);
+
+        let inner: Vec<_> = attrs(top[1].1).collect();
+        assert_eq!(
+            inner,
+            vec![(3, &7_u16.to_ne_bytes()[..]), (4, &b"abcde"[..])]
+        );
+    }
+
+    #[test]
+    fn test_sockaddr() {
+        let v4 = sockaddr(&Endpoint::from_str("192.0.2.1:656").unwrap());
+        assert_eq!(v4.len(), 16);
+        assert_eq!(&v4[2..8], &[0x02, 0x90, 192, 0, 2, 1]);
+
+        let v6 = sockaddr(&Endpoint::from_str("[2001:db8::1]:656").unwrap());
+        assert_eq!(v6.len(), 28);
+        assert_eq!(&v6[2..4], &[0x02, 0x90]);
+        assert_eq!(v6[8], 0x20);
+        assert_eq!(v6[23], 1);
+
+        for s in &["192.0.2.1:656", "[2001:db8::1]:656"] {
+            let e = Endpoint::from_str(s).unwrap();
+            assert_eq!(parse_sockaddr(&sockaddr(&e)), Some(e));
+        }
+    }
+
+    #[test]
+    fn test_nested_too_long() {
+        let mut msg = Message::new(0x20, 0, 1, 1);
+        let pos = msg.begin_nested(2);
+        msg.attr(3, &[0; 40000]);
+        msg.attr(3, &[0; 40000]);
+        let e = msg.end_nested(pos).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_many_allowed_ips() {
+        let pubkey = model::Key::from_bytes([1; 32]);
+        let mut conf = model::Peer {
+            endpoint: None,
+            psk: None,
+            keepalive: 25,
+            ipv4: vec![],
+            ipv6: vec![],
+        };
+        for i in 0..2000_u32 {
+            let addr = model::Ipv4Addr::from(0x0a00_0000 | (i << 8));
+            conf.ipv4.push(model::Ipv4Net::new(addr, 24).unwrap());
+            let addr = model::Ipv6Addr::from((0x2001_0db8_u128 << 96) | (u128::from(i) << 64));
+            conf.ipv6.push(model::Ipv6Net::new(addr, 64).unwrap());
+        }
+
+        let msgs = set_peer_messages(
+            || Message::new(0x20, NLM_F_ACK, WG_CMD_SET_DEVICE, WG_GENL_VERSION),
+            &pubkey,
+            None,
+            &conf,
+        )
+        .unwrap();
+        let total: usize = msgs.iter().map(|m| m.buf.len()).sum();
+        assert!(total > 65536);
+
+        let mut config = model::Config::empty();
+        for (i, mut msg) in msgs.into_iter().enumerate() {
+            let buf = msg.finish(1);
+            assert!(buf.len() <= MAX_MESSAGE_LEN);
+            for (ty, data) in attrs(&buf[NLMSG_HDRLEN + GENL_HDRLEN..]) {
+                assert_eq!(ty, WGDEVICE_A_PEERS);
+                for (_, data) in attrs(data) {
+                    let flags = attrs(data).find(|(ty, _)| *ty == WGPEER_A_FLAGS);
+                    if i == 0 {
+                        let replace = WGPEER_F_REPLACE_ALLOWEDIPS.to_ne_bytes();
+                        assert_eq!(flags, Some((WGPEER_A_FLAGS, &replace[..])));
+                    } else {
+                        assert_eq!(flags, None);
+                    }
+                    parse_peer(&mut config, data).unwrap();
+                }
+            }
+        }
+        assert_eq!(config.peers.get(&pubkey), Some(&conf));
+    }
+}